@@ -0,0 +1,270 @@
+use std;
+use std::collections::HashMap;
+
+use time::Timespec;
+
+use demc::virtc::Input;
+
+
+// How a joystick's simultaneous commands are combined into the one position the joystick takes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    // Average the commands as vectors, so opposing commands cancel out and the stick's strength is their mean's length
+    VectorMean,
+    // Go whichever of the 8 principal directions most commands lean towards, at those commands' average strength
+    MajorityDirection,
+    // Go in the direction closest to all others, at the median strength
+    MedianAngle,
+    // Do what the latest command says
+    MostRecent,
+    // Average the commands as vectors, with every user's commands together counting as much as one command
+    WeightedByUser
+}
+
+impl Aggregation {
+    // The strategy with given name, as written in config files
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vector_mean" => Some(Aggregation::VectorMean),
+            "majority_direction" => Some(Aggregation::MajorityDirection),
+            "median_angle" => Some(Aggregation::MedianAngle),
+            "most_recent" => Some(Aggregation::MostRecent),
+            "weighted_by_user" => Some(Aggregation::WeightedByUser),
+            _ => None
+        }
+    }
+}
+
+// An analog command counting towards its element's position
+#[derive(Clone)]
+pub struct AnalogCommand {
+    pub input: Input,
+    // Who sent the command, if known
+    pub user: Option<String>,
+    pub start_time: Timespec,
+    // Commands that start at the same time are ordered by id
+    pub id: u64
+}
+
+// Whether two inputs are for the same controller element
+pub fn is_same_element(a: &Input, b: &Input) -> bool {
+    match (a, b) {
+        (&Input::Joystick(ref a, _, _), &Input::Joystick(ref b, _, _)) => a == b,
+        (&Input::Button(ref a, _), &Input::Button(ref b, _)) => a == b,
+        (&Input::Pov(ref a, _), &Input::Pov(ref b, _)) => a == b,
+        (&Input::Trigger(ref a, _), &Input::Trigger(ref b, _)) => a == b,
+        _ => false
+    }
+}
+
+// Combine the given analog commands for the same element as the given input, joysticks by the given strategy
+// Triggers and POVs are always averaged. Joysticks and POVs without any commands are centered, and triggers without
+// any released
+pub fn aggregate_analog_inputs(element: &Input, commands: &[&AnalogCommand], aggregation: Aggregation) -> Input {
+    match *element {
+        Input::Joystick(ref name, _, _) => {
+            let vectors: Vec<(u16, f32)> = commands.iter().filter_map(|command| match command.input {
+                Input::Joystick(_, direction, strength) => Some((direction, strength)),
+                _ => None
+            }).collect();
+            if vectors.is_empty() {
+                return Input::Joystick(name.clone(), 0, 0.0);
+            }
+
+            let (direction, strength) = match aggregation {
+                Aggregation::VectorMean => {
+                    let weights = vec![1.0; vectors.len()];
+                    vector_mean(&vectors, &weights)
+                },
+                Aggregation::MajorityDirection => majority_direction(&vectors),
+                Aggregation::MedianAngle => median_angle(&vectors),
+                Aggregation::MostRecent => {
+                    let latest = commands.iter().zip(vectors.iter()).max_by_key(|&(command, _)| {
+                        (command.start_time, command.id)
+                    });
+                    *latest.unwrap().1
+                },
+                Aggregation::WeightedByUser => {
+                    let mut user_command_counts: HashMap<&Option<String>, u32> = HashMap::new();
+                    for command in commands.iter() {
+                        *user_command_counts.entry(&command.user).or_insert(0) += 1;
+                    }
+                    // Commands from unknown users each count on their own
+                    let weights: Vec<f32> = commands.iter().map(|command| match command.user {
+                        Some(_) => 1.0 / user_command_counts[&command.user] as f32,
+                        None => 1.0
+                    }).collect();
+                    vector_mean(&vectors, &weights)
+                }
+            };
+
+            Input::Joystick(name.clone(), direction, strength)
+        },
+        Input::Trigger(ref name, _) => {
+            let mut strength_sum = 0.0f32;
+            for command in commands.iter() {
+                if let Input::Trigger(_, strength) = command.input {
+                    strength_sum += strength;
+                }
+            }
+
+            let strength_avg = if commands.is_empty() { 0.0 } else { strength_sum / commands.len() as f32 };
+            Input::Trigger(name.clone(), strength_avg)
+        },
+        Input::Pov(ref name, _) => {
+            // POVs whose commands cancel out are centered too
+            let (mut x_sum, mut y_sum) = (0.0f32, 0.0f32);
+            for command in commands.iter() {
+                if let Input::Pov(_, Some(direction)) = command.input {
+                    let direction_rad = to_radians(direction);
+                    x_sum += direction_rad.cos();
+                    y_sum += direction_rad.sin();
+                }
+            }
+
+            let mut direction = None;
+            if !commands.is_empty() {
+                let x_avg = x_sum / commands.len() as f32;
+                let y_avg = y_sum / commands.len() as f32;
+
+                if x_avg.hypot(y_avg) >= 0.01 {
+                    direction = Some(to_degrees(y_avg.atan2(x_avg)));
+                }
+            }
+
+            Input::Pov(name.clone(), direction)
+        },
+        Input::Button(ref name, value) => Input::Button(name.clone(), value)
+    }
+}
+
+// The weighted mean of (direction, strength) vectors
+fn vector_mean(vectors: &[(u16, f32)], weights: &[f32]) -> (u16, f32) {
+    let (mut x_sum, mut y_sum, mut weight_sum) = (0.0f32, 0.0f32, 0.0f32);
+    for (&(direction, strength), &weight) in vectors.iter().zip(weights.iter()) {
+        let direction_rad = to_radians(direction);
+        x_sum += direction_rad.cos() * strength * weight;
+        y_sum += direction_rad.sin() * strength * weight;
+        weight_sum += weight;
+    }
+
+    let x_avg = x_sum / weight_sum;
+    let y_avg = y_sum / weight_sum;
+    let strength = x_avg.hypot(y_avg).min(1.0);
+    if strength < 0.001 {
+        return (0, 0.0);
+    }
+
+    (to_degrees(y_avg.atan2(x_avg)), strength)
+}
+
+// The most popular of the 8 principal directions, and the average strength of the vectors pointing that way
+// Ties go to the direction whose first vector came first
+fn majority_direction(vectors: &[(u16, f32)]) -> (u16, f32) {
+    // (first vector's index, vectors, strength sum) by bucket
+    let mut buckets: [(usize, u32, f32); 8] = [(0, 0, 0.0); 8];
+    for (i, &(direction, strength)) in vectors.iter().enumerate() {
+        let bucket = &mut buckets[(((direction as u32 % 360) * 2 + 45) / 90 % 8) as usize];
+        if bucket.1 == 0 {
+            bucket.0 = i;
+        }
+        bucket.1 += 1;
+        bucket.2 += strength;
+    }
+
+    let mut best = 0;
+    for bucket in 1..8 {
+        let (first, count, _) = buckets[bucket];
+        if count > buckets[best].1 || (count == buckets[best].1 && count > 0 && first < buckets[best].0) {
+            best = bucket;
+        }
+    }
+
+    ((best * 45) as u16, buckets[best].2 / buckets[best].1 as f32)
+}
+
+// The direction with the least total angular distance to all the others, and the median strength
+fn median_angle(vectors: &[(u16, f32)]) -> (u16, f32) {
+    let angular_distance = |a: u16, b: u16| {
+        let difference = (a as i32 - b as i32).abs() % 360;
+        std::cmp::min(difference, 360 - difference)
+    };
+
+    let mut median_direction = vectors[0].0;
+    let mut least_distance = std::i32::MAX;
+    for &(direction, _) in vectors.iter() {
+        let distance: i32 = vectors.iter().map(|&(other, _)| angular_distance(direction, other)).sum();
+        if distance < least_distance {
+            median_direction = direction;
+            least_distance = distance;
+        }
+    }
+
+    let mut strengths: Vec<f32> = vectors.iter().map(|&(_, strength)| strength).collect();
+    strengths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = strengths.len() / 2;
+    let median_strength = if strengths.len() % 2 == 0 {
+        (strengths[middle - 1] + strengths[middle]) / 2.0
+    } else {
+        strengths[middle]
+    };
+
+    (median_direction, median_strength)
+}
+
+fn to_radians(direction: u16) -> f32 {
+    (direction as f32) * std::f32::consts::PI / 180.0
+}
+
+// Directions are in whole degrees, counter-clockwise from right, in [0, 360)
+fn to_degrees(direction_rad: f32) -> u16 {
+    let mut direction_rad = direction_rad;
+    if direction_rad < 0.0 {
+        direction_rad = direction_rad + 2.0*std::f32::consts::PI;
+    }
+
+    ((direction_rad * 180.0 / std::f32::consts::PI).round() as u16) % 360
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    use demc::virtc::Input;
+    use demc::aggregation::{Aggregation, AnalogCommand, aggregate_analog_inputs};
+
+    fn push(user: &str, direction: u16, strength: f32, start_s: i64) -> AnalogCommand {
+        AnalogCommand { input: Input::Joystick(String::from("stick"), direction, strength),
+                        user: Some(String::from(user)),
+                        start_time: Timespec::new(start_s, 0),
+                        id: start_s as u64 }
+    }
+
+    fn aggregate(commands: &[AnalogCommand], aggregation: Aggregation) -> (u16, f32) {
+        let element = Input::Joystick(String::from("stick"), 0, 0.0);
+        let command_refs: Vec<&AnalogCommand> = commands.iter().collect();
+        match aggregate_analog_inputs(&element, &command_refs, aggregation) {
+            Input::Joystick(_, direction, strength) => (direction, (strength * 100.0).round() / 100.0),
+            _ => panic!("joysticks aggregate to joysticks")
+        }
+    }
+
+    #[test]
+    fn test_joystick_aggregations() {
+        // Two users pushing right, at different strengths, one user pushing up, and another left
+        let commands = vec![push("a", 0, 1.0, 0), push("a", 10, 0.5, 1), push("b", 90, 1.0, 2), push("c", 180, 1.0, 3)];
+
+        // Right and left cancel out, leaving a bit of right and more up
+        assert_eq!(aggregate(&commands, Aggregation::VectorMean), (66, 0.3));
+        assert_eq!(aggregate(&commands, Aggregation::MajorityDirection), (0, 0.75));
+        assert_eq!(aggregate(&commands, Aggregation::MedianAngle), (10, 1.0));
+        assert_eq!(aggregate(&commands, Aggregation::MostRecent), (180, 1.0));
+        // a's two commands count as one, so c's left outweighs a's right, and the stick leans up and a bit left
+        assert_eq!(aggregate(&commands, Aggregation::WeightedByUser), (104, 0.36));
+
+        // Opposing commands leave the stick centered, rather than pushed at full strength
+        let opposing = vec![push("a", 0, 1.0, 0), push("b", 180, 1.0, 0)];
+        assert_eq!(aggregate(&opposing, Aggregation::VectorMean), (0, 0.0));
+    }
+}
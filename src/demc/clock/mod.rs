@@ -0,0 +1,214 @@
+use std;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+
+use time::{Timespec, Duration, get_time};
+
+use demc::TimedInput;
+
+
+// A source of time for DemC, its command listener and RecC
+// The command listener does all of its waiting through its clock, so that a virtual clock can decide when time passes
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timespec;
+
+    // Wait for a line of commands to come in on the given receiver, giving up once the clock reaches the deadline, if
+    // any
+    fn recv_until(&self, rx: &Receiver<Vec<TimedInput>>, deadline: Option<Timespec>)
+                  -> Result<Vec<TimedInput>, RecvTimeoutError>;
+
+    // Wait until the clock reaches the deadline
+    fn sleep_until(&self, deadline: Timespec);
+
+    // A command listener is about to start, or has stopped, waiting on this clock
+    fn listener_started(&self) {}
+    fn listener_stopped(&self) {}
+}
+
+// Tells a clock that a command listener is waiting on it for as long as it's alive
+// The listener's thread holds it, so that the clock hears the listener has stopped even if the listener panics
+pub struct ListenerGuard {
+    clock: Arc<Clock>
+}
+
+impl ListenerGuard {
+    pub fn new(clock: Arc<Clock>) -> Self {
+        clock.listener_started();
+        ListenerGuard { clock: clock }
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        self.clock.listener_stopped();
+    }
+}
+
+// Convert a length of time into the std kind, for sleeping on; negative lengths become zero
+pub fn to_std_duration(duration: Duration) -> std::time::Duration {
+    let us = match duration.num_microseconds() {
+        Some(us) if us > 0 => us,
+        Some(_) => 0,
+        None => std::i64::MAX
+    };
+
+    std::time::Duration::new((us / 1000000) as u64, ((us % 1000000) * 1000) as u32)
+}
+
+
+// The wall clock
+#[derive(Clone)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Timespec {
+        get_time()
+    }
+
+    fn recv_until(&self, rx: &Receiver<Vec<TimedInput>>, deadline: Option<Timespec>)
+                  -> Result<Vec<TimedInput>, RecvTimeoutError> {
+        match deadline {
+            Some(deadline) => rx.recv_timeout(to_std_duration(deadline - self.now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        }
+    }
+
+    fn sleep_until(&self, deadline: Timespec) {
+        thread::sleep(to_std_duration(deadline - self.now()));
+    }
+}
+
+
+struct VirtualTime {
+    // What time the listener is at
+    now: Timespec,
+    // What time the listener may go up to
+    target: Timespec,
+    listeners: u32,
+    // Whether the listener has nothing left to do before target
+    idle: bool
+}
+
+// A clock that only moves when told to
+// Advancing it lets the command listener catch up to the new time, acting on each event at the time it's due, and
+// returns once the listener has nothing left to do. A virtual clock drives at most one command listener
+#[derive(Clone)]
+pub struct VirtualClock {
+    time: Arc<(Mutex<VirtualTime>, Condvar)>
+}
+
+impl VirtualClock {
+    pub fn new(start: Timespec) -> Self {
+        VirtualClock { time: Arc::new((Mutex::new(VirtualTime { now: start, target: start, listeners: 0, idle: false }),
+                                       Condvar::new())) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let &(ref lock, ref condvar) = &*self.time;
+        let mut time = lock.lock().unwrap();
+
+        time.target = time.target + duration;
+        time.idle = false;
+        condvar.notify_all();
+
+        while time.listeners > 0 && !(time.idle && time.now == time.target) {
+            time = condvar.wait(time).unwrap();
+        }
+        time.now = time.target;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Timespec {
+        self.time.0.lock().unwrap().now
+    }
+
+    fn recv_until(&self, rx: &Receiver<Vec<TimedInput>>, deadline: Option<Timespec>)
+                  -> Result<Vec<TimedInput>, RecvTimeoutError> {
+        let &(ref lock, ref condvar) = &*self.time;
+        let mut time = lock.lock().unwrap();
+
+        loop {
+            // Check for commands while holding the lock, so that anything sent before the clock was advanced is seen
+            // before the listener calls itself idle
+            match rx.try_recv() {
+                Ok(commands) => return Ok(commands),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => ()
+            }
+
+            match deadline {
+                Some(deadline) if deadline <= time.target => {
+                    if deadline > time.now {
+                        time.now = deadline;
+                    }
+                    return Err(RecvTimeoutError::Timeout);
+                },
+                _ => ()
+            }
+
+            time.now = time.target;
+            time.idle = true;
+            condvar.notify_all();
+
+            // Senders don't wake us, so check back every so often
+            time = condvar.wait_timeout(time, std::time::Duration::from_millis(1)).unwrap().0;
+        }
+    }
+
+    fn sleep_until(&self, deadline: Timespec) {
+        let &(ref lock, ref condvar) = &*self.time;
+        let mut time = lock.lock().unwrap();
+
+        while deadline > time.target {
+            time.now = time.target;
+            time.idle = true;
+            condvar.notify_all();
+            time = condvar.wait(time).unwrap();
+        }
+        if deadline > time.now {
+            time.now = deadline;
+        }
+    }
+
+    fn listener_started(&self) {
+        self.time.0.lock().unwrap().listeners += 1;
+    }
+
+    fn listener_stopped(&self) {
+        let &(ref lock, ref condvar) = &*self.time;
+        // A listener that panicked may have poisoned the lock; the count is still good
+        let mut time = match lock.lock() {
+            Ok(time) => time,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        time.listeners -= 1;
+        condvar.notify_all();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use time::{Timespec, Duration};
+
+    use demc::clock::{Clock, VirtualClock, ListenerGuard};
+
+    #[test]
+    fn test_advancing_past_a_panicked_listener_returns() {
+        let clock = VirtualClock::new(Timespec::new(1000, 0));
+        let listener_guard = ListenerGuard::new(Arc::new(clock.clone()));
+        let listener = thread::spawn(move|| {
+            let _listener_guard = listener_guard;
+            panic!("listener failed");
+        });
+        assert!(listener.join().is_err());
+
+        clock.advance(Duration::seconds(1));
+        assert_eq!(clock.now(), Timespec::new(1001, 0));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use toml;
+
+use demc::layout::{ControllerLayout, read_toml_file};
+use demc::aggregation::Aggregation;
+
+
+// Longest time, in milliseconds, that a line's commands may take to start, unless configured otherwise
+pub const DEFAULT_MAX_LINE_DURATION: u32 = 30000;
+// Illegal combinations, unless configured otherwise: GameCube games reset on start+b+x, so none of those three may be
+// pressed while the other two are held
+const DEFAULT_ILLEGAL_COMBINATIONS: [[&'static str; 3]; 3] = [["start", "b", "x"], ["b", "start", "x"],
+                                                              ["x", "b", "start"]];
+
+// Limits on what chat may ask of a controller, on top of what the controller itself can do, and how chat's
+// simultaneous commands are combined
+// Per-input limits are keyed by element name: joystick, trigger and POV names as well as button names
+// Constraints are read from the [constraints] section of a TOML file; see tppm.toml.example
+#[derive(Clone)]
+pub struct ControllerConstraints {
+    // (button, buttons) tuples; the button may not be pressed while all of the buttons are held
+    pub illegal_combinations: Vec<(String, Vec<String>)>,
+    // Map of input names to the longest time, in milliseconds, they may be held for
+    // Inputs not in this map fall back to DemC's defaults
+    pub max_durations: HashMap<String, u32>,
+    // Map of joystick and trigger names to the lowest strength, in percent, they may be given
+    pub min_strengths: HashMap<String, u32>,
+    // Map of input names to the most times they may appear in one line
+    pub max_repeats: HashMap<String, u32>,
+    // Longest time, in milliseconds, that a line's commands may take to start
+    pub max_line_duration: u32,
+    // Most commands, delays included, that one line may have
+    pub max_commands_per_line: Option<u32>,
+    // Map of joystick names to how their simultaneous commands are combined
+    // Joysticks not in this map take the mean of their commands
+    pub aggregations: HashMap<String, Aggregation>
+}
+
+impl ControllerConstraints {
+    // No constraints beyond the defaults
+    pub fn new() -> Self {
+        ControllerConstraints { illegal_combinations: Vec::new(),
+                                max_durations: HashMap::new(),
+                                min_strengths: HashMap::new(),
+                                max_repeats: HashMap::new(),
+                                max_line_duration: DEFAULT_MAX_LINE_DURATION,
+                                max_commands_per_line: None,
+                                aggregations: HashMap::new() }
+    }
+
+    // Load the constraints described by the given TOML configuration file, on top of the button max durations the
+    // given layout declares
+    // Err(1): Unable to open config file
+    // Err(2): Unable to parse config file as TOML
+    // Err(3): [constraints] section malformed
+    // Err(4): An illegal combination is malformed or names an unknown button
+    // Err(5): An input's constraints are malformed or name an unknown input
+    pub fn from_config_file(path: &str, layout: &ControllerLayout) -> Result<Self, u8> {
+        let tree = try!(read_toml_file(path));
+        ControllerConstraints::from_toml(&tree, layout)
+    }
+
+    // Build constraints from the [constraints] section of a TOML tree, which may be missing altogether
+    // Without an illegal_combinations key, the default illegal combinations apply; an empty list turns them off
+    // Err(3): [constraints] section malformed
+    // Err(4): An illegal combination is malformed or names an unknown button
+    // Err(5): An input's constraints are malformed or name an unknown input
+    pub fn from_toml(tree: &toml::Value, layout: &ControllerLayout) -> Result<Self, u8> {
+        let mut constraints = ControllerConstraints::new();
+        constraints.max_durations = layout.max_durations.clone();
+
+        if let Some(max_line_duration_value) = tree.lookup("constraints.max_line_duration") {
+            match max_line_duration_value.as_integer() {
+                Some(max_line_duration) if max_line_duration >= 0 => {
+                    constraints.max_line_duration = max_line_duration as u32;
+                },
+                _ => return Err(3)
+            }
+        }
+
+        if let Some(max_commands_value) = tree.lookup("constraints.max_commands_per_line") {
+            match max_commands_value.as_integer() {
+                Some(max_commands) if max_commands > 0 => {
+                    constraints.max_commands_per_line = Some(max_commands as u32);
+                },
+                _ => return Err(3)
+            }
+        }
+
+        // Illegal combinations are lists of buttons: the first may not be pressed while all of the rest are held
+        match tree.lookup("constraints.illegal_combinations") {
+            Some(combinations_value) => {
+                let combinations_slice = match combinations_value.as_slice() {
+                    Some(slice) => slice,
+                    None => return Err(4)
+                };
+                for combination_value in combinations_slice.iter() {
+                    let mut buttons = Vec::new();
+                    for button_value in combination_value.as_slice().unwrap_or(&[]).iter() {
+                        match button_value.as_str() {
+                            Some(button) if layout.buttons.contains_key(button) => buttons.push(String::from(button)),
+                            _ => return Err(4)
+                        }
+                    }
+                    if buttons.len() < 2 {
+                        return Err(4);
+                    }
+
+                    let button = buttons.remove(0);
+                    constraints.illegal_combinations.push((button, buttons));
+                }
+            },
+            None => {
+                for buttons in DEFAULT_ILLEGAL_COMBINATIONS.iter() {
+                    let other_buttons = buttons[1..].iter().map(|&button| String::from(button)).collect();
+                    constraints.illegal_combinations.push((String::from(buttons[0]), other_buttons));
+                }
+            }
+        }
+
+        if let Some(inputs_value) = tree.lookup("constraints.inputs") {
+            let inputs_table = match inputs_value.as_table() {
+                Some(table) => table,
+                None => return Err(5)
+            };
+            for (input_name, input_value) in inputs_table.iter() {
+                let known_input = layout.joysticks.contains_key(input_name) ||
+                                  layout.triggers.contains_key(input_name) ||
+                                  layout.povs.contains_key(input_name) ||
+                                  layout.buttons.contains_key(input_name);
+                if !known_input || input_value.as_table().is_none() {
+                    return Err(5);
+                }
+
+                // Only joysticks combine their commands
+                if let Some(aggregation_value) = input_value.lookup("aggregation") {
+                    match aggregation_value.as_str().and_then(Aggregation::from_name) {
+                        Some(aggregation) if layout.joysticks.contains_key(input_name) => {
+                            constraints.aggregations.insert(input_name.clone(), aggregation);
+                        },
+                        _ => return Err(5)
+                    }
+                }
+
+                for &key in ["max_duration", "min_strength", "max_repeats"].iter() {
+                    let limit = match input_value.lookup(key) {
+                        Some(limit_value) => match limit_value.as_integer() {
+                            Some(limit) if limit >= 0 => limit as u32,
+                            _ => return Err(5)
+                        },
+                        None => continue
+                    };
+
+                    let limit_map = match key {
+                        "max_duration" => &mut constraints.max_durations,
+                        "min_strength" => &mut constraints.min_strengths,
+                        _ => &mut constraints.max_repeats
+                    };
+                    limit_map.insert(input_name.clone(), limit);
+                }
+            }
+        }
+
+        Ok(constraints)
+    }
+}
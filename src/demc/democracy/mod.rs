@@ -0,0 +1,389 @@
+use toml;
+use time::{Timespec, Duration};
+
+use demc::TimedInput;
+use demc::virtc::Input;
+use demc::layout::read_toml_file;
+
+
+// How long, in milliseconds, a democracy vote stays open, unless configured otherwise
+pub const DEFAULT_VOTE_WINDOW: u32 = 10000;
+
+// Meter defaults, in percent of the meter, and milliseconds
+pub const DEFAULT_METER_VOTE_WEIGHT: f32 = 2.0;
+pub const DEFAULT_METER_HALF_LIFE: u32 = 60000;
+pub const DEFAULT_DEMOCRACY_THRESHOLD: f32 = 75.0;
+pub const DEFAULT_ANARCHY_THRESHOLD: f32 = 25.0;
+const NEUTRAL_METER_VALUE: f32 = 50.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputMode {
+    // Every command chat sends is acted on, and simultaneous analog commands are averaged
+    Anarchy,
+    // Lines chat sends are votes, and only the winning line of each vote window is acted on
+    Democracy
+}
+
+// How DemC should treat chat's commands, read from the [democracy] section of a TOML file; see tppm.toml.example
+#[derive(Clone)]
+pub struct DemocracyConfig {
+    pub mode: InputMode,
+    // How long, in milliseconds, each vote stays open
+    pub vote_window: u32,
+    pub meter: MeterConfig
+}
+
+impl DemocracyConfig {
+    pub fn new() -> Self {
+        DemocracyConfig { mode: InputMode::Anarchy, vote_window: DEFAULT_VOTE_WINDOW, meter: MeterConfig::new() }
+    }
+
+    // Err(1): Unable to open config file
+    // Err(2): Unable to parse config file as TOML
+    // Err(3): [democracy] section malformed
+    pub fn from_config_file(path: &str) -> Result<Self, u8> {
+        let tree = try!(read_toml_file(path));
+        DemocracyConfig::from_toml(&tree)
+    }
+
+    // Err(3): [democracy] section malformed
+    pub fn from_toml(tree: &toml::Value) -> Result<Self, u8> {
+        let mut config = DemocracyConfig::new();
+
+        if let Some(mode_value) = tree.lookup("democracy.mode") {
+            config.mode = match mode_value.as_str() {
+                Some("anarchy") => InputMode::Anarchy,
+                Some("democracy") => InputMode::Democracy,
+                _ => return Err(3)
+            };
+        }
+
+        if let Some(vote_window_value) = tree.lookup("democracy.vote_window") {
+            match vote_window_value.as_integer() {
+                Some(vote_window) if vote_window > 0 => {
+                    config.vote_window = vote_window as u32;
+                },
+                _ => return Err(3)
+            }
+        }
+
+        // The meter's settings are all numbers, in percent of the meter except for the half-life, in milliseconds
+        for &key in ["vote_weight", "half_life", "democracy_threshold", "anarchy_threshold"].iter() {
+            let setting = match tree.lookup(&format!("democracy.meter.{}", key)) {
+                Some(setting_value) => match setting_value.as_integer().map(|setting| setting as f64)
+                                                          .or(setting_value.as_float()) {
+                    Some(setting) if setting >= 0.0 => setting,
+                    _ => return Err(3)
+                },
+                None => continue
+            };
+
+            match key {
+                "vote_weight" => { config.meter.vote_weight = setting as f32; },
+                "half_life" => { config.meter.half_life = setting as u32; },
+                "democracy_threshold" => { config.meter.democracy_threshold = setting as f32; },
+                _ => { config.meter.anarchy_threshold = setting as f32; }
+            }
+        }
+        // Neutral must lie strictly between the thresholds, so that only votes switch modes, never the meter's decay
+        if config.meter.anarchy_threshold >= NEUTRAL_METER_VALUE
+                || config.meter.democracy_threshold <= NEUTRAL_METER_VALUE
+                || config.meter.democracy_threshold > 100.0 {
+            return Err(3);
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Clone)]
+pub struct MeterConfig {
+    // How far, in percent, each vote moves the meter
+    pub vote_weight: f32,
+    // How long, in milliseconds, it takes the meter to decay halfway back to neutral
+    pub half_life: u32,
+    // Democracy takes over once the meter reaches this percentage...
+    pub democracy_threshold: f32,
+    // ...and anarchy takes back over once it falls to this one
+    pub anarchy_threshold: f32
+}
+
+impl MeterConfig {
+    pub fn new() -> Self {
+        MeterConfig { vote_weight: DEFAULT_METER_VOTE_WEIGHT,
+                      half_life: DEFAULT_METER_HALF_LIFE,
+                      democracy_threshold: DEFAULT_DEMOCRACY_THRESHOLD,
+                      anarchy_threshold: DEFAULT_ANARCHY_THRESHOLD }
+    }
+}
+
+// An anarchy/democracy meter
+// Chat votes for a mode to move the meter towards it: 0% is all anarchy and 100% all democracy. Left alone, the meter
+// decays back towards 50%, and since that lies between the thresholds, only votes can switch modes
+pub struct Meter {
+    config: MeterConfig,
+    value: f32,
+    last_update: Option<Timespec>,
+    // A mode mods have locked chat into, whatever the meter says
+    locked_mode: Option<InputMode>
+}
+
+impl Meter {
+    pub fn new(config: MeterConfig) -> Self {
+        Meter { config: config, value: NEUTRAL_METER_VALUE, last_update: None, locked_mode: None }
+    }
+
+    // The meter's value at the given time, in percent
+    pub fn get_value(&mut self, time_now: Timespec) -> f32 {
+        self.decay(time_now);
+        self.value
+    }
+
+    pub fn get_locked_mode(&self) -> Option<InputMode> {
+        self.locked_mode
+    }
+
+    pub fn lock_mode(&mut self, mode: Option<InputMode>) {
+        self.locked_mode = mode;
+    }
+
+    // Count a vote for the given mode at the given time, returning the mode to switch to, if the vote tips the meter
+    // over the current mode's threshold and no mode is locked
+    pub fn vote(&mut self, mode: InputMode, time_now: Timespec, current_mode: InputMode) -> Option<InputMode> {
+        self.decay(time_now);
+        self.value = match mode {
+            InputMode::Anarchy => (self.value - self.config.vote_weight).max(0.0),
+            InputMode::Democracy => (self.value + self.config.vote_weight).min(100.0)
+        };
+
+        if self.locked_mode.is_some() {
+            return None;
+        }
+        match current_mode {
+            InputMode::Anarchy if self.value >= self.config.democracy_threshold => Some(InputMode::Democracy),
+            InputMode::Democracy if self.value <= self.config.anarchy_threshold => Some(InputMode::Anarchy),
+            _ => None
+        }
+    }
+
+    fn decay(&mut self, time_now: Timespec) {
+        if let Some(last_update) = self.last_update {
+            let elapsed = (time_now - last_update).num_milliseconds();
+            if elapsed > 0 && self.config.half_life > 0 {
+                let remaining = 0.5f32.powf(elapsed as f32 / self.config.half_life as f32);
+                self.value = NEUTRAL_METER_VALUE + (self.value - NEUTRAL_METER_VALUE) * remaining;
+            }
+        }
+        self.last_update = Some(time_now);
+    }
+}
+
+
+struct Vote {
+    key: String,
+    // The line as first cast
+    commands: Vec<TimedInput>,
+    count: u32
+}
+
+// The votes cast during one democracy vote window
+// Lines are tallied by what they'd do rather than how they were written, so "a" and "a500ms" are the same vote, and
+// a whole line counts as a single vote
+pub struct Ballot {
+    vote_window: u32,
+    votes: Vec<Vote>,
+    // Each ballot opened gets a new number, so that closing a stale one does nothing
+    number: u64,
+    is_open: bool
+}
+
+impl Ballot {
+    pub fn new(vote_window: u32) -> Self {
+        Ballot { vote_window: vote_window, votes: Vec::new(), number: 0, is_open: false }
+    }
+
+    pub fn get_vote_window(&self) -> u32 {
+        self.vote_window
+    }
+
+    pub fn set_vote_window(&mut self, vote_window: u32) {
+        self.vote_window = vote_window;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open
+    }
+
+    // Open a new ballot at the given time, returning its number and when it should close
+    pub fn open(&mut self, time_now: Timespec) -> (u64, Timespec) {
+        self.votes.clear();
+        self.number += 1;
+        self.is_open = true;
+
+        (self.number, time_now + Duration::milliseconds(self.vote_window as i64))
+    }
+
+    pub fn cast(&mut self, commands: Vec<TimedInput>) {
+        if commands.is_empty() {
+            return;
+        }
+
+        let key = describe_line(&commands);
+        if let Some(vote) = self.votes.iter_mut().find(|vote| vote.key == key) {
+            vote.count += 1;
+            return;
+        }
+        self.votes.push(Vote { key: key, commands: commands, count: 1 });
+    }
+
+    // Every line voted for and its number of votes, most votes first
+    // Ties go to the line first voted for
+    pub fn get_tally(&self) -> Vec<(String, u32)> {
+        let mut tally: Vec<(String, u32)> = self.votes.iter().map(|vote| (vote.key.clone(), vote.count)).collect();
+        // sort_by is stable, so tied lines stay in the order they were first cast
+        tally.sort_by(|a, b| b.1.cmp(&a.1));
+        tally
+    }
+
+    // Close the ballot with given number, returning the winning line, if any
+    // Closing a ballot that's already been closed or replaced does nothing
+    pub fn close(&mut self, number: u64) -> Option<Vec<TimedInput>> {
+        if !self.is_open || number != self.number {
+            return None;
+        }
+        self.is_open = false;
+
+        let mut winner: Option<Vote> = None;
+        for vote in self.votes.drain(..) {
+            let beats_winner = match winner {
+                Some(ref winner) => vote.count > winner.count,
+                None => true
+            };
+            if beats_winner {
+                winner = Some(vote);
+            }
+        }
+
+        winner.map(|vote| vote.commands)
+    }
+
+    // Throw away any votes, and forget the ballot is open
+    pub fn clear(&mut self) {
+        self.votes.clear();
+        self.is_open = false;
+    }
+}
+
+// Describe what a line does, independently of when it was sent
+// eg. "a(500ms) @560ms control_stick:90deg/100%(250ms)"
+pub fn describe_line(commands: &[TimedInput]) -> String {
+    let first_start_time = match commands.first() {
+        Some(command) => command.start_time,
+        None => return String::new()
+    };
+
+    let descriptions: Vec<String> = commands.iter().map(|command| {
+        let input = match command.command {
+            Input::Button(ref name, _) => name.clone(),
+            Input::Joystick(ref name, direction, strength) => {
+                format!("{}:{}deg/{}%", name, direction, (strength * 100.0).round() as u32)
+            },
+            Input::Pov(ref name, Some(direction)) => format!("{}:{}deg", name, direction),
+            Input::Pov(ref name, None) => format!("{}:center", name),
+            Input::Trigger(ref name, strength) => format!("{}:{}%", name, (strength * 100.0).round() as u32)
+        };
+        let description = format!("{}({}ms)", input, command.duration.num_milliseconds());
+
+        match (command.start_time - first_start_time).num_milliseconds() {
+            0 => description,
+            offset => format!("@{}ms {}", offset, description)
+        }
+    }).collect();
+
+    descriptions.join(" ")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::{Timespec, Duration};
+    use toml;
+
+    use demc::TimedInput;
+    use demc::virtc::Input;
+    use demc::democracy::{Ballot, Meter, MeterConfig, InputMode, DemocracyConfig};
+
+    fn press(name: &str, start_ms: i64, duration_ms: i64) -> TimedInput {
+        TimedInput { start_time: Timespec::new(0, 0) + Duration::milliseconds(start_ms),
+                     duration: Duration::milliseconds(duration_ms),
+                     command: Input::Button(String::from(name), true),
+                     user: None }
+    }
+
+    #[test]
+    fn test_ballot_tallies_whole_lines() {
+        let mut ballot = Ballot::new(10000);
+        let (number, closing_time) = ballot.open(Timespec::new(0, 0));
+        assert_eq!(closing_time, Timespec::new(10, 0));
+
+        ballot.cast(vec![press("a", 0, 500), press("b", 560, 250)]);
+        ballot.cast(vec![press("b", 100, 500)]);
+        ballot.cast(vec![press("a", 2000, 500), press("b", 2560, 250)]);
+        ballot.cast(vec![press("b", 3000, 500)]);
+        ballot.cast(vec![press("a", 4000, 500)]);
+
+        assert_eq!(ballot.get_tally(), vec![(String::from("a(500ms) @560ms b(250ms)"), 2),
+                                            (String::from("b(500ms)"), 2),
+                                            (String::from("a(500ms)"), 1)]);
+
+        // Ties go to the line voted for first, and the line is played as first cast
+        let winner = ballot.close(number).unwrap();
+        assert_eq!(winner.len(), 2);
+        assert_eq!(winner[1].start_time, Timespec::new(0, 560000000));
+        assert!(ballot.get_tally().is_empty());
+        assert!(ballot.close(number).is_none());
+    }
+
+    #[test]
+    fn test_meter_switches_modes_with_hysteresis() {
+        let config = MeterConfig { vote_weight: 10.0, half_life: 1000, democracy_threshold: 75.0,
+                                   anarchy_threshold: 25.0 };
+        let mut meter = Meter::new(config);
+        let start = Timespec::new(0, 0);
+
+        assert_eq!(meter.vote(InputMode::Democracy, start, InputMode::Anarchy), None);
+        assert_eq!(meter.vote(InputMode::Democracy, start, InputMode::Anarchy), None);
+        assert_eq!(meter.vote(InputMode::Democracy, start, InputMode::Anarchy), Some(InputMode::Democracy));
+
+        // Halfway back to neutral after a half-life, which is still democracy
+        let later = start + Duration::seconds(1);
+        assert!((meter.get_value(later) - 65.0).abs() < 0.01);
+        for _ in 0..3 {
+            assert_eq!(meter.vote(InputMode::Anarchy, later, InputMode::Democracy), None);
+        }
+        assert_eq!(meter.vote(InputMode::Anarchy, later, InputMode::Democracy), Some(InputMode::Anarchy));
+
+        // Mods' locks hold no matter the votes
+        meter.lock_mode(Some(InputMode::Anarchy));
+        for _ in 0..10 {
+            assert_eq!(meter.vote(InputMode::Democracy, later, InputMode::Anarchy), None);
+        }
+        assert_eq!(meter.get_value(later), 100.0);
+    }
+    #[test]
+    fn test_meter_thresholds_lie_either_side_of_neutral() {
+        let thresholds = |democracy_threshold: u32, anarchy_threshold: u32| {
+            let tree: toml::Value = format!("[democracy.meter]\ndemocracy_threshold = {}\nanarchy_threshold = {}",
+                                            democracy_threshold, anarchy_threshold).parse().unwrap();
+            DemocracyConfig::from_toml(&tree).map(|config| config.meter.democracy_threshold).ok()
+        };
+
+        assert_eq!(thresholds(51, 49), Some(51.0));
+        assert_eq!(thresholds(100, 0), Some(100.0));
+        assert_eq!(thresholds(101, 25), None);
+        assert_eq!(thresholds(50, 25), None);
+        assert_eq!(thresholds(75, 50), None);
+        assert_eq!(thresholds(40, 25), None);
+        assert_eq!(thresholds(75, 60), None);
+    }
+}
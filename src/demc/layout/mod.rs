@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use toml;
+
+use demc::virtc::VJoystickDriver;
+use demc::scheduler::DEFAULT_FRAMES_PER_SECOND;
+
+
+// A description of a virtual controller's hardware: which axes it has, how those pair up into joysticks and which
+// words move them or stand alone as triggers, which buttons it has and what chat may call them, and which POV hats it
+// has and which words move them
+// Layouts are read from the [controller] section of a TOML file, or from the TOML file that section's "profile" key
+// names. See profiles/ for examples
+pub struct ControllerLayout {
+    pub name: String,
+    // Frame rate of the games played with this controller, which DemC times inputs to
+    pub frames_per_second: u32,
+    // Map of axis names to vJoy axis HID constants
+    pub axes: HashMap<String, u32>,
+    // Map of joystick names to (x axis, y axis) tuples
+    pub joysticks: HashMap<String, (String, String)>,
+    // Map of direction words to (joystick, direction in degrees) tuples
+    pub directions: HashMap<String, (String, u16)>,
+    // Map of trigger names to axis names
+    pub triggers: HashMap<String, String>,
+    // Map of button names to one-based button indices
+    pub buttons: HashMap<String, u8>,
+    // Map of button names to the longest time, in milliseconds, they may be held for
+    pub max_durations: HashMap<String, u32>,
+    // Map of button aliases to the button names they stand for
+    pub aliases: HashMap<String, String>,
+    // Map of POV names to (one-based POV number, whether the POV is continuous) tuples
+    pub povs: HashMap<String, (u8, bool)>,
+    // Map of POV direction words to (POV, direction in degrees) tuples
+    pub pov_directions: HashMap<String, (String, u16)>
+}
+
+// Translate an axis name as written in a layout into a vJoy axis HID constant
+// Axes may be given by HID usage name ("x", "rx", "sl0", ...) or by raw HID constant
+fn parse_axis_hid(value: &toml::Value) -> Option<u32> {
+    if let Some(hid) = value.as_integer() {
+        return match hid {
+            0x30...0x37 => Some(hid as u32),
+            _ => None
+        };
+    }
+
+    match value.as_str() {
+        Some("x") => Some(0x30),
+        Some("y") => Some(0x31),
+        Some("z") => Some(0x32),
+        Some("rx") => Some(0x33),
+        Some("ry") => Some(0x34),
+        Some("rz") => Some(0x35),
+        Some("sl0") => Some(0x36),
+        Some("sl1") => Some(0x37),
+        _ => None
+    }
+}
+
+// Err(1): Unable to open file
+// Err(2): Unable to parse file as TOML
+pub fn read_toml_file<P: AsRef<Path>>(path: P) -> Result<toml::Value, u8> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Err(1)
+    };
+    let mut file_string = String::new();
+    file.read_to_string(&mut file_string);
+
+    match file_string.parse() {
+        Ok(tree) => Ok(tree),
+        Err(_) => Err(2)
+    }
+}
+
+impl ControllerLayout {
+    // Load the controller layout described by the given TOML configuration file
+    // A relative profile path is taken relative to the configuration file's directory, not the current one
+    // Err(1): Unable to open config file or profile
+    // Err(2): Unable to parse config file or profile as TOML
+    // Err(3): [controller] section missing or malformed
+    // Err(4): An axis is malformed
+    // Err(5): A joystick is malformed or refers to an unknown axis
+    // Err(6): A button is malformed
+    // Err(7): A POV is malformed
+    // Err(8): A trigger is malformed or refers to an unknown axis
+    // Err(9): A word is defined more than once
+    pub fn from_config_file(path: &str) -> Result<Self, u8> {
+        let tree = try!(read_toml_file(path));
+
+        // A [controller] section may defer to a separate profile file
+        match tree.lookup("controller.profile") {
+            Some(profile) => match profile.as_str() {
+                Some(profile_path) => {
+                    let profile_path = match Path::new(path).parent() {
+                        Some(config_dir) => config_dir.join(profile_path),
+                        None => Path::new(profile_path).to_path_buf()
+                    };
+                    let profile_tree = try!(read_toml_file(&profile_path));
+                    ControllerLayout::from_toml(&profile_tree)
+                },
+                None => Err(3)
+            },
+            None => ControllerLayout::from_toml(&tree)
+        }
+    }
+
+    // Build a controller layout from the [controller] section of a TOML tree
+    // Err(3): [controller] section missing or malformed
+    // Err(4): An axis is malformed
+    // Err(5): A joystick is malformed or refers to an unknown axis
+    // Err(6): A button is malformed
+    // Err(7): A POV is malformed
+    // Err(8): A trigger is malformed or refers to an unknown axis
+    // Err(9): A word is defined more than once
+    pub fn from_toml(tree: &toml::Value) -> Result<Self, u8> {
+        let name = match tree.lookup("controller.name") {
+            Some(name) => match name.as_str() {
+                Some(name) => String::from(name),
+                None => return Err(3)
+            },
+            None => return Err(3)
+        };
+
+        let frames_per_second = match tree.lookup("controller.frames_per_second") {
+            Some(frames_per_second_value) => match frames_per_second_value.as_integer() {
+                Some(frames_per_second) if frames_per_second > 0 => frames_per_second as u32,
+                _ => return Err(3)
+            },
+            None => DEFAULT_FRAMES_PER_SECOND
+        };
+
+        let mut axes = HashMap::new();
+        if let Some(axes_value) = tree.lookup("controller.axes") {
+            let axes_table = match axes_value.as_table() {
+                Some(table) => table,
+                None => return Err(4)
+            };
+            for (axis_name, hid_value) in axes_table.iter() {
+                match parse_axis_hid(hid_value) {
+                    Some(hid) => { axes.insert(axis_name.clone(), hid); },
+                    None => return Err(4)
+                }
+            }
+        }
+
+        let mut joysticks = HashMap::new();
+        let mut directions = HashMap::new();
+        if let Some(joysticks_value) = tree.lookup("controller.joysticks") {
+            let joysticks_table = match joysticks_value.as_table() {
+                Some(table) => table,
+                None => return Err(5)
+            };
+            for (joystick_name, joystick_value) in joysticks_table.iter() {
+                let x = match joystick_value.lookup("x").and_then(|x| x.as_str()) {
+                    Some(x) => String::from(x),
+                    None => return Err(5)
+                };
+                let y = match joystick_value.lookup("y").and_then(|y| y.as_str()) {
+                    Some(y) => String::from(y),
+                    None => return Err(5)
+                };
+                if !axes.contains_key(&x) || !axes.contains_key(&y) {
+                    return Err(5);
+                }
+
+                let directions_table = match joystick_value.lookup("directions").and_then(|d| d.as_table()) {
+                    Some(table) => table,
+                    None => return Err(5)
+                };
+                for (word, degrees_value) in directions_table.iter() {
+                    match degrees_value.as_integer() {
+                        Some(degrees) if degrees >= 0 && degrees < 360 => {
+                            if directions.insert(word.clone(), (joystick_name.clone(), degrees as u16)).is_some() {
+                                return Err(9);
+                            }
+                        },
+                        _ => return Err(5)
+                    }
+                }
+
+                joysticks.insert(joystick_name.clone(), (x, y));
+            }
+        }
+
+        let mut triggers = HashMap::new();
+        if let Some(triggers_value) = tree.lookup("controller.triggers") {
+            let triggers_table = match triggers_value.as_table() {
+                Some(table) => table,
+                None => return Err(8)
+            };
+            for (trigger_name, axis_value) in triggers_table.iter() {
+                match axis_value.as_str() {
+                    Some(axis) if axes.contains_key(axis) => {
+                        triggers.insert(trigger_name.clone(), String::from(axis));
+                    },
+                    _ => return Err(8)
+                }
+            }
+        }
+
+        // Buttons are either a bare index, or a table with an index and optionally a max duration and aliases
+        let mut buttons = HashMap::new();
+        let mut max_durations = HashMap::new();
+        let mut aliases = HashMap::new();
+        if let Some(buttons_value) = tree.lookup("controller.buttons") {
+            let buttons_table = match buttons_value.as_table() {
+                Some(table) => table,
+                None => return Err(6)
+            };
+            for (button_name, button_value) in buttons_table.iter() {
+                let index_value = match button_value.as_table() {
+                    Some(_) => button_value.lookup("index"),
+                    None => Some(button_value)
+                };
+                match index_value.and_then(|index| index.as_integer()) {
+                    Some(index) if index > 0 && index < 256 => { buttons.insert(button_name.clone(), index as u8); },
+                    _ => return Err(6)
+                }
+
+                if let Some(max_duration_value) = button_value.lookup("max_duration") {
+                    match max_duration_value.as_integer() {
+                        Some(max_duration) if max_duration >= 0 => {
+                            max_durations.insert(button_name.clone(), max_duration as u32);
+                        },
+                        _ => return Err(6)
+                    }
+                }
+
+                if let Some(aliases_value) = button_value.lookup("aliases") {
+                    let aliases_slice = match aliases_value.as_slice() {
+                        Some(slice) => slice,
+                        None => return Err(6)
+                    };
+                    for alias_value in aliases_slice.iter() {
+                        match alias_value.as_str() {
+                            Some(alias) => if aliases.insert(String::from(alias), button_name.clone()).is_some() {
+                                return Err(9);
+                            },
+                            None => return Err(6)
+                        }
+                    }
+                }
+            }
+        }
+
+        // POVs are a one-based POV number, whether the POV is continuous (default false), and direction words
+        let mut povs = HashMap::new();
+        let mut pov_directions = HashMap::new();
+        if let Some(povs_value) = tree.lookup("controller.povs") {
+            let povs_table = match povs_value.as_table() {
+                Some(table) => table,
+                None => return Err(7)
+            };
+            for (pov_name, pov_value) in povs_table.iter() {
+                let pov = match pov_value.lookup("pov").and_then(|pov| pov.as_integer()) {
+                    Some(pov) if pov > 0 && pov < 256 => pov as u8,
+                    _ => return Err(7)
+                };
+                let continuous = match pov_value.lookup("continuous") {
+                    Some(continuous_value) => match continuous_value.as_bool() {
+                        Some(continuous) => continuous,
+                        None => return Err(7)
+                    },
+                    None => false
+                };
+
+                let directions_table = match pov_value.lookup("directions").and_then(|d| d.as_table()) {
+                    Some(table) => table,
+                    None => return Err(7)
+                };
+                for (word, degrees_value) in directions_table.iter() {
+                    match degrees_value.as_integer() {
+                        Some(degrees) if degrees >= 0 && degrees < 360 => {
+                            if pov_directions.insert(word.clone(), (pov_name.clone(), degrees as u16)).is_some() {
+                                return Err(9);
+                            }
+                        },
+                        _ => return Err(7)
+                    }
+                }
+
+                povs.insert(pov_name.clone(), (pov, continuous));
+            }
+        }
+
+        // Each word chat may use stands for one thing, but a trigger may share its name with its button, since "50%l"
+        // and "l" tell the two apart
+        let mut words = HashSet::new();
+        let trigger_words = triggers.keys().filter(|&trigger_name| !buttons.contains_key(trigger_name));
+        for word in directions.keys().chain(pov_directions.keys()).chain(trigger_words)
+                                     .chain(buttons.keys()).chain(aliases.keys()) {
+            if !words.insert(word) {
+                return Err(9);
+            }
+        }
+
+        Ok(ControllerLayout { name: name,
+                              frames_per_second: frames_per_second,
+                              axes: axes,
+                              joysticks: joysticks,
+                              directions: directions,
+                              triggers: triggers,
+                              buttons: buttons,
+                              max_durations: max_durations,
+                              aliases: aliases,
+                              povs: povs,
+                              pov_directions: pov_directions })
+    }
+
+    // Build the (vJoy axis HID constant, minimum value, maximum value) axis map the virtc traits expect, querying the
+    // given driver for each axis' range
+    // Err(1): unable to get an axis' minimum
+    // Err(2): unable to get an axis' maximum
+    pub fn get_axis_map(&self, driver: &VJoystickDriver) -> Result<HashMap<String, (u32, i64, i64)>, u8> {
+        let mut axes = HashMap::new();
+
+        for (name, &hid) in self.axes.iter() {
+            let min = match driver.get_axis_min(hid) {
+                Ok(min) => min,
+                Err(_) => return Err(1)
+            };
+            let max = match driver.get_axis_max(hid) {
+                Ok(max) => max,
+                Err(_) => return Err(2)
+            };
+            axes.insert(name.clone(), (hid, min, max));
+        }
+
+        Ok(axes)
+    }
+}
+
+
+// Load one of the profiles in profiles/ by name, wherever the tests are run from
+#[cfg(test)]
+pub fn load_test_profile(name: &str) -> ControllerLayout {
+    ControllerLayout::from_config_file(&format!("{}/profiles/{}.toml", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    use toml;
+
+    use demc::layout::{ControllerLayout, load_test_profile};
+
+    #[test]
+    fn test_profiles_are_found_next_to_the_config_file() {
+        // A config file and its profile, away from the directory we're run from
+        let config_dir = env::temp_dir().join(format!("tppm-layout-test-{}", process::id()));
+        fs::create_dir_all(config_dir.join("my_profiles")).unwrap();
+        let profile_path = config_dir.join("my_profiles/n64.toml");
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/n64.toml"), &profile_path).unwrap();
+        let config_path = config_dir.join("tppm.toml");
+        let config = b"[controller]\nprofile = \"my_profiles/n64.toml\"\n";
+        fs::File::create(&config_path).unwrap().write_all(config).unwrap();
+
+        let layout = ControllerLayout::from_config_file(config_path.to_str().unwrap());
+        fs::remove_dir_all(&config_dir).unwrap();
+        assert_eq!(layout.ok().map(|layout| layout.name), Some(load_test_profile("n64").name));
+    }
+    #[test]
+    fn test_words_are_defined_once() {
+        let layout_with_buttons = |buttons: &str| {
+            let tree: toml::Value = format!(r##"
+                [controller]
+                name = "test"
+
+                [controller.axes]
+                jx = "x"
+                jy = "y"
+                lt = "z"
+
+                [controller.joysticks.stick]
+                x = "jx"
+                y = "jy"
+                directions = {{ right = 0, up = 90, left = 180, down = 270 }}
+
+                [controller.triggers]
+                l = "lt"
+
+                [controller.buttons]
+                {}
+            "##, buttons).parse().unwrap();
+            ControllerLayout::from_toml(&tree).map(|layout| layout.buttons.len())
+        };
+
+        // A trigger may share its name with its button, but an alias may not shadow another button or word
+        assert_eq!(layout_with_buttons("a = 1\nl = 2"), Ok(2));
+        assert_eq!(layout_with_buttons("a = 1\nb = { index = 2, aliases = [\"a\"] }"), Err(9));
+        assert_eq!(layout_with_buttons(concat!("a = { index = 1, aliases = [\"jump\"] }\n",
+                                               "b = { index = 2, aliases = [\"jump\"] }")), Err(9));
+        assert_eq!(layout_with_buttons("a = { index = 1, aliases = [\"up\"] }"), Err(9));
+        assert_eq!(layout_with_buttons("left = 1"), Err(9));
+
+        // Nor may a POV's directions share words with buttons, as a d-pad's might
+        let pov = "\n[controller.povs.dpad]\npov = 1\ndirections = { dright = 0, dup = 90 }";
+        assert_eq!(layout_with_buttons(&format!("a = 1{}", pov)), Ok(1));
+        assert_eq!(layout_with_buttons(&format!("dup = 9{}", pov)), Err(9));
+    }
+}
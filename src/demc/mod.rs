@@ -1,137 +1,141 @@
-use std;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
-use std::sync::Mutex;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
+use std::fmt;
 use std::thread;
 
-use time;
-use time::{Timespec, Duration, get_time};
-
-use regex::Regex;
+use time::{Timespec, Duration};
 
 pub mod virtc;
-pub mod vgcnc;
-pub mod vn64c;
-
-use demc::virtc::{AcceptsInputs, HasJoysticks, HasButtons};
+pub mod layout;
+pub mod vgenc;
+pub mod recc;
+pub mod parser;
+pub mod constraints;
+pub mod scheduler;
+pub mod clock;
+pub mod democracy;
+pub mod aggregation;
+pub mod ratelimit;
+
+use demc::virtc::{AcceptsInputs, HasJoysticks, HasTriggers, HasButtons, HasPovs};
+use demc::parser::{Command, Limit, ParseError, Vocabulary};
+use demc::scheduler::{Scheduler, Event, DEFAULT_FRAMES_PER_SECOND, get_frame_duration};
+use demc::clock::{Clock, RealClock, ListenerGuard};
+use demc::ratelimit::{RateLimiter, RateLimitConfig, Rejection};
+use demc::aggregation::{Aggregation, AnalogCommand, is_same_element, aggregate_analog_inputs};
+use demc::democracy::{InputMode, Ballot, Meter, MeterConfig, DemocracyConfig, DEFAULT_VOTE_WINDOW};
+pub use demc::constraints::ControllerConstraints;
 
 
 const MILLISECONDS_PER_SECOND: u32 = 1000;
-const FRAMES_PER_SECOND: u32 = 60; //@todo accept at runtime
-const MILLISECONDS_PER_FRAME: u32 = 17; //@todo calculate this at runtime
 
 const DEFAULT_JOYSTICK_COMMAND_DURATION: u32 = MILLISECONDS_PER_SECOND/4;
 const DEFAULT_BUTTON_COMMAND_DURATION: u32 = MILLISECONDS_PER_SECOND/2;
 
 const MAX_JOYSTICK_COMMAND_DURATION: u32 = 5000;
 const MAX_BUTTON_COMMAND_DURATION: u32 = 5000;
-const MAX_B_BUTTON_COMMAND_DURATION: u32 = 30000;
-const MAX_X_BUTTON_COMMAND_DURATION: u32 = 30000;
-const MAX_R_BUTTON_COMMAND_DURATION: u32 = 10000;
-const MAX_START_BUTTON_COMMAND_DURATION: u32 = 500;
-const MAX_DURATION_PER_LINE: u32 = 30000;
 const MILLISECONDS_PER_DOT: u32 = 250;
 
-const JOYSTICK_TO_JOYSTICK_DELAY: u32 = MILLISECONDS_PER_FRAME*2;
-const BUTTON_TO_JOYSTICK_DELAY: u32 = 0;
-const BUTTON_TO_BUTTON_DELAY: u32 = MILLISECONDS_PER_FRAME*3;
-const JOYSTICK_TO_BUTTON_UNDELAY: u32 = MILLISECONDS_PER_FRAME;
-const SIMULTANEOUS_COMMAND_DELAY: u32 = MILLISECONDS_PER_FRAME;
-
-
-fn get_button_guard_index_n64(name: &str) -> usize {
-    // Zero-based indexing of enum values
-    //@todo this really shouldn't be necessary
-    match name {
-        "a" => 0,
-        "b" => 1,
-        "z" => 2,
-        "l" => 3,
-        "r" => 4,
-        "start" => 5,
-        "cup" => 6,
-        "cdown" => 7,
-        "cleft" => 8,
-        "cright" => 9,
-        "dup" => 10,
-        "ddown" => 11,
-        "dleft" => 12,
-        "dright" => 13,
-        _ => panic!("uhhh")
-    }
-}
+// Delays between consecutive commands, in frames
+const JOYSTICK_TO_JOYSTICK_FRAMES: u32 = 2;
+const BUTTON_TO_JOYSTICK_FRAMES: u32 = 0;
+const BUTTON_TO_BUTTON_FRAMES: u32 = 3;
+const JOYSTICK_TO_BUTTON_UNDELAY_FRAMES: u32 = 1;
+const SIMULTANEOUS_COMMAND_FRAMES: u32 = 1;
 
-fn get_button_guard_index_gcn(name: &str) -> usize {
-    // Zero-based indexing of enum values
-    //@todo this really shouldn't be necessary
-    match name {
-        "a" => 0,
-        "b" => 1,
-        "x" => 2,
-        "y" => 3,
-        "z" => 4,
-        "l" => 5,
-        "r" => 6,
-        "start" => 7,
-        "dup" => 8,
-        "ddown" => 9,
-        "dleft" => 10,
-        "dright" => 11,
-        _ => panic!("uhhh")
-    }
-}
 
 #[derive(Clone)]
 pub struct TimedInput {
     pub start_time: Timespec,
     pub duration: Duration,
-    pub command: virtc::Input
+    pub command: virtc::Input,
+    // Who sent the command, if known
+    pub user: Option<String>
+}
+
+// Why a line of chat wasn't acted on
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Parse(ParseError),
+    // The line would have overrun a rate limit budget
+    RateLimited(Rejection)
+}
+
+impl From<ParseError> for CommandError {
+    fn from(err: ParseError) -> Self {
+        CommandError::Parse(err)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Parse(ref err) => write!(f, "{}", err),
+            CommandError::RateLimited(ref rejection) => write!(f, "rate limited: {}", rejection)
+        }
+    }
 }
 
 trait CommandedAsynchronously {
-    fn get_tx_command(&self) -> &mpsc::Sender<TimedInput>;
+    fn get_tx_command(&self) -> &mpsc::Sender<Vec<TimedInput>>;
     fn get_command_listener(&self) -> &thread::JoinHandle<()>;
 }
 
 pub trait ChatInterfaced: CommandedAsynchronously {
-    fn get_regex(&self) -> &Regex;
-    fn parse_string_as_commands(&self, msg: &String) -> Option<Vec<TimedInput>>;
-
-    fn handle_commands(&self, commands: &String) -> Result<(), ()> {
-        match self.parse_string_as_commands(commands) {
-            Some(commands) => {
-                for command in commands.iter() {
-                    self.add_command(command);
-                }
-                Ok(())
-            },
-            None => Err(())
-        }
+    fn get_vocabulary(&self) -> &Vocabulary;
+    fn parse_string_as_commands(&self, msg: &String) -> Result<Vec<TimedInput>, ParseError>;
+    // Spend a line of commands from the given user from the rate limit budgets
+    fn admit_commands(&self, user: &String, commands: &[TimedInput]) -> Result<(), Rejection>;
+
+    fn handle_commands(&self, commands: &String) -> Result<(), ParseError> {
+        let commands = try!(self.parse_string_as_commands(commands));
+        self.add_commands(commands);
+        Ok(())
     }
 
-    fn add_command(&self, command: &TimedInput) {
-        self.get_tx_command().send(command.clone());
+    // Handle a line of commands from the given chat user, whose commands may be weighted together
+    // Unlike lines handled without a user, these count against rate limits
+    fn handle_commands_from(&self, user: &String, commands: &String) -> Result<(), CommandError> {
+        let mut commands = try!(self.parse_string_as_commands(commands));
+        try!(self.admit_commands(user, &commands).map_err(CommandError::RateLimited));
+        for command in commands.iter_mut() {
+            command.user = Some(user.clone());
+        }
+        self.add_commands(commands);
+        Ok(())
     }
-}
-
 
-pub struct ControllerConstraints {
-    pub illegal_combinations: Vec<(String, Vec<String>)>,
-    //max_durations: Vec<
+    // Hand a line's worth of commands to the command listener, which acts on them or counts them as a vote
+    fn add_commands(&self, commands: Vec<TimedInput>) {
+        if !commands.is_empty() {
+            self.get_tx_command().send(commands);
+        }
+    }
 }
 
 
 // A democratized virtual controller
 pub struct DemC<T> {
     controller: Arc<T>,
-    re: Regex,
-    tx_command: mpsc::Sender<TimedInput>,
+    constraints: ControllerConstraints,
+    directions: HashMap<String, (String, u16)>,
+    aliases: HashMap<String, String>,
+    pov_directions: HashMap<String, (String, u16)>,
+    vocabulary: Vocabulary,
+    milliseconds_per_frame: u32,
+    clock: Arc<Clock>,
+    mode: Arc<Mutex<InputMode>>,
+    ballot: Arc<Mutex<Ballot>>,
+    meter: Mutex<Meter>,
+    rate_limiter: Mutex<RateLimiter>,
+    tx_command: mpsc::Sender<Vec<TimedInput>>,
     command_listener: thread::JoinHandle<()>
 }
 
 impl<T> CommandedAsynchronously for DemC<T> {
-    fn get_tx_command(&self) -> &mpsc::Sender<TimedInput> {
+    fn get_tx_command(&self) -> &mpsc::Sender<Vec<TimedInput>> {
         return &self.tx_command;
     }
 
@@ -142,551 +146,732 @@ impl<T> CommandedAsynchronously for DemC<T> {
 
 
 impl<T> ChatInterfaced for DemC<T> {
-    fn get_regex(&self) -> &Regex {
-        return &self.re;
-    }
-    
-    // Attempt to parse an IRC message into a list of controller commands
-    //@todo just use a custom state machine rather than regex, this has to be insanely slow
-    //@todo this function is huge
-    fn parse_string_as_commands(&self, msg: &String) -> Option<Vec<TimedInput>> {
-        let mut last_cap_end = None;
-        let mut cumulative_delay: u32 = 0;
-        let mut last_command: Option<TimedInput> = None;
-        let mut cap_start_zero = false;
-        let mut final_cap_end: usize = 0;
-        let mut res: Vec<TimedInput> = Vec::new();
+    fn get_vocabulary(&self) -> &Vocabulary {
+        return &self.vocabulary;
+    }
 
-        for cap in self.get_regex().captures_iter(&msg.to_lowercase()) {
-            let (cap_start, cap_end) = cap.pos(0).unwrap();
+    // A line costs its user and chat one line, plus the controller-milliseconds of all of its commands
+    fn admit_commands(&self, user: &String, commands: &[TimedInput]) -> Result<(), Rejection> {
+        let milliseconds = commands.iter().fold(0, |sum, command| sum + command.duration.num_milliseconds());
+        self.rate_limiter.lock().unwrap().admit(user, milliseconds as u32, self.clock.now())
+    }
 
-            // Raise a flag if this capture starts at the first string position - after iterating over all captures, we'll
-            // make sure it was raised, and if it wasn't, we didn't parse a valid command message
-            if cap_start == 0 {
-                cap_start_zero = true;
-            }
+    // Attempt to parse an IRC message into a list of controller commands, timed relative to now
+    fn parse_string_as_commands(&self, msg: &String) -> Result<Vec<TimedInput>, ParseError> {
+        let commands = try!(parser::parse(&msg.to_lowercase(), self.get_vocabulary()));
 
-            // Make sure that all captures are continuous; that we're parsing commands and only commands
-            // eg. we don't want "hahah" to parse as two "a" commands
-            if let Some(last_cap_end) = last_cap_end {
-                if last_cap_end != cap_start {
-                    return None;
-                }
+        if let Some(max_commands) = self.constraints.max_commands_per_line {
+            if commands.len() > max_commands as usize {
+                return Err(ParseError::LimitExceeded(commands[max_commands as usize].position,
+                                                     Limit::CommandCount(commands.len() as u32, max_commands)));
             }
+        }
 
-            // Store the last capture's ending position - after iterating over all captures, we'll make sure the last
-            // one ended
-            final_cap_end = cap_end;
+        let time_now = self.clock.now();
+        let mut cumulative_delay: u32 = 0;
+        let mut last_command: Option<TimedInput> = None;
+        let mut repeats: HashMap<String, u32> = HashMap::new();
+        let mut res: Vec<TimedInput> = Vec::new();
 
-            // Our regex should match on exactly one of three groups: "joystick", "button", or "delay"
-            if let Some(_) = cap.name("joystick") {
-                match last_command {
-                    Some(command) => {
-                        cumulative_delay += command.duration.num_milliseconds() as u32;
-                        match command.command {
-                            virtc::Input::Joystick(_, _, _) => {
-                                cumulative_delay += JOYSTICK_TO_JOYSTICK_DELAY;
-                            },
-                            virtc::Input::Button(_, _) => {
-                                cumulative_delay += BUTTON_TO_JOYSTICK_DELAY;
-                            }
-                        }
-                    },
-                    None =>  ()
-                }
-                // joystick command - should have one, two, or four groups:
-                // "joystick_strength" (optional)
-                // "joystick_direction" (mandatory)
-                // "joystick_duration" (optional),
-                // "joystick_duration_units" (optional; must be present if joystick_duration is)
-                let mut joystick_strength: f32 = 1.0;
-                let mut joystick_direction: u16 = 0;
-                let mut joystick_name = "";
-                let mut joystick_duration: u32 = DEFAULT_JOYSTICK_COMMAND_DURATION;
-                if let Some(jscap) = cap.name("joystick_strength") {
-                    match jscap.parse::<u8>() {
-                        Ok(strength_u8) => { joystick_strength = strength_u8 as f32 / 100.0; },
-                        _ => return None
+        for parsed in commands.iter() {
+            let position = parsed.position;
+
+            // Work out what each element command does and for how long, checking it against our limits
+            // Delay commands just push back the commands after them
+            let (input, duration) = match parsed.command {
+                Command::Joystick(ref word, strength, duration) => {
+                    let (name, direction) = match self.directions.get(word) {
+                        Some(&(ref name, direction)) => (name, direction),
+                        None => return Err(ParseError::UnknownWord(position))
+                    };
+                    let strength = strength.unwrap_or(100);
+                    let duration = duration.unwrap_or(DEFAULT_JOYSTICK_COMMAND_DURATION);
+                    try!(self.check_input_limits(position, word, name, Some(strength), duration,
+                                                 MAX_JOYSTICK_COMMAND_DURATION, &mut repeats));
+
+                    (virtc::Input::Joystick(name.clone(), direction, strength as f32 / 100.0), duration)
+                },
+                Command::Pov(ref word, duration) => {
+                    // POVs are timed like joysticks
+                    let (name, direction) = match self.pov_directions.get(word) {
+                        Some(&(ref name, direction)) => (name, direction),
+                        None => return Err(ParseError::UnknownWord(position))
+                    };
+                    let duration = duration.unwrap_or(DEFAULT_JOYSTICK_COMMAND_DURATION);
+                    try!(self.check_input_limits(position, word, name, None, duration,
+                                                 MAX_JOYSTICK_COMMAND_DURATION, &mut repeats));
+
+                    (virtc::Input::Pov(name.clone(), Some(direction)), duration)
+                },
+                Command::Trigger(ref name, strength, duration) => {
+                    // Triggers are timed like joysticks
+                    let duration = duration.unwrap_or(DEFAULT_JOYSTICK_COMMAND_DURATION);
+                    try!(self.check_input_limits(position, name, name, Some(strength), duration,
+                                                 MAX_JOYSTICK_COMMAND_DURATION, &mut repeats));
+
+                    (virtc::Input::Trigger(name.clone(), strength as f32 / 100.0), duration)
+                },
+                Command::Button(ref word, duration) => {
+                    let name = match self.aliases.get(word) {
+                        Some(name) => name,
+                        None => word
+                    };
+                    let duration = duration.unwrap_or(DEFAULT_BUTTON_COMMAND_DURATION);
+                    try!(self.check_input_limits(position, word, name, None, duration,
+                                                 MAX_BUTTON_COMMAND_DURATION, &mut repeats));
+
+                    (virtc::Input::Button(name.clone(), true), duration)
+                },
+                Command::Delay(delay) => {
+                    if let Some(command) = last_command {
+                        cumulative_delay = cumulative_delay.saturating_add(command.duration.num_milliseconds() as u32);
                     }
-                }
-                if let Some(jdcap) = cap.name("joystick_direction") {
-                    match jdcap {
-                        "cup" => { joystick_direction = 90; joystick_name = "c_stick"; },
-                        "cdown" => { joystick_direction = 270; joystick_name = "c_stick"; },
-                        "cleft" => { joystick_direction = 180; joystick_name = "c_stick"; },
-                        "cright" => { joystick_direction = 0; joystick_name = "c_stick"; },
-                        "up" => { joystick_direction = 90; joystick_name = "control_stick"; },
-                        "down" => { joystick_direction = 270; joystick_name = "control_stick"; },
-                        "left" => { joystick_direction = 180; joystick_name = "control_stick"; },
-                        "right" => { joystick_direction = 0; joystick_name = "control_stick"; },
-                        _ => ()
+                    cumulative_delay = cumulative_delay.saturating_add(delay);
+                    last_command = None;
+                    try!(self.check_line_duration(position, cumulative_delay));
+                    continue;
+                },
+                Command::Frame => {
+                    cumulative_delay += self.milliseconds_per_frame;
+                    last_command = None;
+                    try!(self.check_line_duration(position, cumulative_delay));
+                    continue;
+                },
+                Command::FrameAfter | Command::Dot => {
+                    if let Some(command) = last_command {
+                        cumulative_delay += command.duration.num_milliseconds() as u32;
                     }
-                } else {
-                    return None;
+                    cumulative_delay += match parsed.command {
+                        Command::Dot => MILLISECONDS_PER_DOT,
+                        _ => self.milliseconds_per_frame
+                    };
+                    last_command = None;
+                    try!(self.check_line_duration(position, cumulative_delay));
+                    continue;
                 }
-                if let Some(jdcap) = cap.name("joystick_duration") {
-                    match jdcap.parse::<u32>() {
-                        Ok(duration_u32) => { joystick_duration = duration_u32; },
-                        _ => return None
-                    }
-
-                    if let Some(jdcap_units) = cap.name("joystick_duration_units") {
-                        if jdcap_units == "s" {
-                            joystick_duration *= MILLISECONDS_PER_SECOND;
+            };
+
+            // Element commands start once the previous element command ends, give or take some slack that depends on
+            // what kinds of elements the two commands are for
+            if let Some(command) = last_command {
+                let last_duration = command.duration.num_milliseconds() as u32;
+                let frame = self.milliseconds_per_frame;
+                match (command.command, &input) {
+                    (virtc::Input::Button(_, _), &virtc::Input::Button(_, _)) => {
+                        cumulative_delay += last_duration + BUTTON_TO_BUTTON_FRAMES*frame;
+                    },
+                    (virtc::Input::Button(_, _), _) => {
+                        cumulative_delay += last_duration + BUTTON_TO_JOYSTICK_FRAMES*frame;
+                    },
+                    (_, &virtc::Input::Button(_, _)) => {
+                        cumulative_delay += last_duration;
+                        if last_duration >= JOYSTICK_TO_BUTTON_UNDELAY_FRAMES*frame {
+                            cumulative_delay -= JOYSTICK_TO_BUTTON_UNDELAY_FRAMES*frame;
                         }
-                    } else {
-                        return None;
+                    },
+                    (_, _) => {
+                        cumulative_delay += last_duration + JOYSTICK_TO_JOYSTICK_FRAMES*frame;
                     }
                 }
+            }
+            try!(self.check_line_duration(position, cumulative_delay));
 
-                // treat joystick commands with strength <0%, >100% or duration >5s as invalid
-                if joystick_strength > 1.0 || joystick_strength < 0.0 || joystick_duration > MAX_JOYSTICK_COMMAND_DURATION {
-                    return None;
-                }
+            let command = TimedInput { start_time: time_now + Duration::milliseconds(cumulative_delay as i64),
+                                       duration: Duration::milliseconds(duration as i64),
+                                       command: input,
+                                       user: None };
+            res.push(command.clone());
 
-                let time_now = get_time();
-                let command = TimedInput { start_time: time_now + Duration::milliseconds(cumulative_delay as i64),
-                                           duration: Duration::milliseconds(joystick_duration as i64),
-                                           command: virtc::Input::Joystick(String::from(joystick_name), joystick_direction, joystick_strength) };
-                res.push(command.clone());
-
-                last_command = Some(command);
-            } else if let Some(_) = cap.name("button") {
-                match last_command {
-                    Some(command) => {
-                        match command.command {
-                            virtc::Input::Joystick(_, _, _) => {
-                                cumulative_delay += command.duration.num_milliseconds() as u32;
-                                if command.duration.num_milliseconds() >= JOYSTICK_TO_BUTTON_UNDELAY as i64 {
-                                    cumulative_delay -= JOYSTICK_TO_BUTTON_UNDELAY;
-                                }
-                            },
-                            virtc::Input::Button(_, _) => {
-                                cumulative_delay += command.duration.num_milliseconds() as u32;
-                                cumulative_delay += BUTTON_TO_BUTTON_DELAY;
-                            }
-                        }
-                    },
-                    None =>  ()
-                }
-                // button command - should have one or three groups:
-                // "button_name" (mandatory)
-                // "button_duration" (optional),
-                // "button_duration_units" (optional; must be present if joystick_duration is)
-                let button_name;
-                let mut button_duration: u32 = DEFAULT_BUTTON_COMMAND_DURATION;
-
-                if let Some(bncap) = cap.name("button_name") {
-                    button_name = bncap;
-                } else {
-                    return None;
-                }
+            last_command = Some(command);
+        }
 
-                if let Some(bdcap) = cap.name("button_duration") {
-                    match bdcap.parse::<u32>() {
-                        Ok(duration_u32) => { button_duration = duration_u32; },
-                        _ => return None
-                    }
+        Ok(res)
+    }
+}
 
-                    if let Some(bdcap_units) = cap.name("button_duration_units") {
-                        if bdcap_units == "s" {
-                            button_duration *= MILLISECONDS_PER_SECOND;
-                        }
-                    } else {
-                        return None;
-                    }
-                }
+impl<T> DemC<T> {
+    // The underlying virtual controller, eg. for querying its current state
+    pub fn get_controller(&self) -> &T {
+        &self.controller
+    }
 
-                //@todo support per-button max durations in the passed constraints
-                let max_duration = match button_name {
-                    "b" => MAX_B_BUTTON_COMMAND_DURATION,
-                    "x" => MAX_X_BUTTON_COMMAND_DURATION,
-                    "r" => MAX_R_BUTTON_COMMAND_DURATION,
-                    "start" => MAX_START_BUTTON_COMMAND_DURATION,
-                    _ => MAX_BUTTON_COMMAND_DURATION
-                };
-                if button_duration > max_duration {
-                    return None;
-                }
+    pub fn get_mode(&self) -> InputMode {
+        *self.mode.lock().unwrap()
+    }
 
-                let time_now = get_time();
-                let command = TimedInput { start_time: time_now + Duration::milliseconds(cumulative_delay as i64),
-                                                  duration: Duration::milliseconds(button_duration as i64),
-                                                  command: virtc::Input::Button(String::from(button_name), true)};
-                res.push(command.clone());
-
-                last_command = Some(command);
-            } else if let Some(dncap) = cap.name("delay_duration") {
-                let mut delay_duration;
-                match dncap.parse::<u32>() {
-                    Ok(duration_u32) => { delay_duration = duration_u32; },
-                    _ => return None
-                }
+    // Switch input mode, throwing away any vote in progress
+    pub fn set_mode(&self, mode: InputMode) {
+        *self.mode.lock().unwrap() = mode;
+        self.ballot.lock().unwrap().clear();
+    }
 
-                if let Some(dncap_units) = cap.name("delay_duration_units") {
-                    if dncap_units == "s" {
-                        delay_duration *= MILLISECONDS_PER_SECOND;
-                    }
-                } else {
-                    return None;
-                }
-                
-                match last_command {
-                    Some(command) => { cumulative_delay += command.duration.num_milliseconds() as u32; },
-                    None =>  ()
-                }
-                cumulative_delay += delay_duration;
-                
-                last_command = None
-            } else if let Some(dcap) = cap.name("delay_hardcode") {
-                // delay command - only one argument, the delay to insert
-                match dcap {
-                    "+" => { cumulative_delay += MILLISECONDS_PER_FRAME; },
-                    "!" => { 
-                        match last_command {
-                            Some(command) => { cumulative_delay += command.duration.num_milliseconds() as u32; },
-                            None => ()
-                        }
-                        cumulative_delay += MILLISECONDS_PER_FRAME;
-                    },
-                    "." => {
-                        match last_command {
-                            Some(command) => { cumulative_delay += command.duration.num_milliseconds() as u32; },
-                            None => ()
-                        }
-                        cumulative_delay += MILLISECONDS_PER_DOT;
-                    },
-                    _ => { return None; }
-                }
-                last_command = None
+    pub fn get_vote_window(&self) -> u32 {
+        self.ballot.lock().unwrap().get_vote_window()
+    }
+
+    // Set how long, in milliseconds, ballots opened from now on stay open
+    pub fn set_vote_window(&self, vote_window: u32) {
+        self.ballot.lock().unwrap().set_vote_window(vote_window);
+    }
+
+    // The lines voted for in the current democracy ballot and their numbers of votes, most votes first
+    pub fn get_tally(&self) -> Vec<(String, u32)> {
+        self.ballot.lock().unwrap().get_tally()
+    }
+
+    // Apply the mode, vote window and meter settings of a [democracy] section
+    pub fn configure_democracy(&self, config: &DemocracyConfig) {
+        self.set_vote_window(config.vote_window);
+        self.set_mode(config.mode);
+        *self.meter.lock().unwrap() = Meter::new(config.meter.clone());
+    }
+
+    // Note that a line from the given user was dropped for overrunning a rate limit, and say whether to tell them so:
+    // only their first dropped line in each rate limit window is worth telling them about
+    pub fn note_rejection(&self, user: &String) -> bool {
+        self.rate_limiter.lock().unwrap().note_rejection(user, self.clock.now())
+    }
+
+    // Replace the rate limit budgets, refilling them all
+    pub fn set_rate_limits(&self, config: &RateLimitConfig) {
+        *self.rate_limiter.lock().unwrap() = RateLimiter::new(config.clone(), self.clock.now());
+    }
+
+    // The anarchy/democracy meter's value, in percent: 0 is all anarchy and 100 all democracy
+    pub fn get_meter(&self) -> f32 {
+        self.meter.lock().unwrap().get_value(self.clock.now())
+    }
+
+    // Count a chat message of "anarchy" or "democracy" as a vote on the meter, switching modes if it tips the meter
+    // Returns whether the message was a mode vote
+    pub fn handle_mode_vote(&self, msg: &String) -> bool {
+        let mode = match msg.trim().to_lowercase().as_ref() {
+            "anarchy" => InputMode::Anarchy,
+            "democracy" => InputMode::Democracy,
+            _ => return false
+        };
+
+        let new_mode = self.meter.lock().unwrap().vote(mode, self.clock.now(), self.get_mode());
+        if let Some(new_mode) = new_mode {
+            self.set_mode(new_mode);
+        }
+        true
+    }
+
+    pub fn get_locked_mode(&self) -> Option<InputMode> {
+        self.meter.lock().unwrap().get_locked_mode()
+    }
+
+    // Lock chat into the given mode, whatever the meter says, or with None, let the meter decide again
+    pub fn lock_mode(&self, mode: Option<InputMode>) {
+        self.meter.lock().unwrap().lock_mode(mode);
+        if let Some(mode) = mode {
+            if mode != self.get_mode() {
+                self.set_mode(mode);
             }
+        }
+    }
 
-            last_cap_end = Some(cap_end);
+    // Check an element command against our constraints, counting it towards its input's repeats
+    // word is the element as written in chat, and name the input it stands for
+    // Err(ParseError::LimitExceeded): the command breaks a limit
+    fn check_input_limits(&self, position: usize, word: &String, name: &String, strength: Option<u32>, duration: u32,
+                          default_max_duration: u32, repeats: &mut HashMap<String, u32>) -> Result<(), ParseError> {
+        if let Some(strength) = strength {
+            if strength > 100 {
+                return Err(ParseError::LimitExceeded(position, Limit::Strength(strength)));
+            }
+            if let Some(&min_strength) = self.constraints.min_strengths.get(name) {
+                if strength < min_strength {
+                    return Err(ParseError::LimitExceeded(position, Limit::MinStrength(word.clone(), strength,
+                                                                                      min_strength)));
+                }
+            }
         }
 
-        if cumulative_delay > MAX_DURATION_PER_LINE {
-                return None;
+        let max_duration = match self.constraints.max_durations.get(name) {
+            Some(max_duration) => *max_duration,
+            None => default_max_duration
+        };
+        if duration > max_duration {
+            return Err(ParseError::LimitExceeded(position, Limit::Duration(word.clone(), duration, max_duration)));
         }
 
-        if final_cap_end != msg.len() {
-            return None;
+        let count = repeats.entry(name.clone()).or_insert(0);
+        *count += 1;
+        if let Some(&max_repeats) = self.constraints.max_repeats.get(name) {
+            if *count > max_repeats {
+                return Err(ParseError::LimitExceeded(position, Limit::Repeats(word.clone(), max_repeats)));
+            }
         }
 
-        if cap_start_zero != true {
-            return None;
+        Ok(())
+    }
+
+    // Err(ParseError::LimitExceeded): the command at the given position would start too far into its line
+    fn check_line_duration(&self, position: usize, cumulative_delay: u32) -> Result<(), ParseError> {
+        let max_line_duration = self.constraints.max_line_duration;
+        if cumulative_delay > max_line_duration {
+            return Err(ParseError::LimitExceeded(position, Limit::LineDuration(cumulative_delay, max_line_duration)));
         }
 
-        return Some(res);
+        Ok(())
     }
 }
 
 impl<T> DemC<T> where T: AcceptsInputs + Send + Sync + 'static {
-    pub fn new(controller: T, constraints: ControllerConstraints) -> Result<DemC<T>, u8> where T: HasButtons + HasJoysticks {
+    pub fn new(controller: T, constraints: ControllerConstraints) -> Result<DemC<T>, u8>
+            where T: HasButtons + HasJoysticks + HasTriggers + HasPovs {
+        DemC::with_frame_rate(controller, constraints, DEFAULT_FRAMES_PER_SECOND)
+    }
+
+    // Make a democratized controller whose inputs change on the frame boundaries of a game running at the given frame
+    // rate
+    pub fn with_frame_rate(controller: T, constraints: ControllerConstraints, frames_per_second: u32)
+            -> Result<DemC<T>, u8> where T: HasButtons + HasJoysticks + HasTriggers + HasPovs {
+        DemC::with_clock(controller, constraints, frames_per_second, Arc::new(RealClock))
+    }
+
+    // Make a democratized controller that takes its time from the given clock, both when timing chat's commands and
+    // when acting on them
+    pub fn with_clock(controller: T, constraints: ControllerConstraints, frames_per_second: u32, clock: Arc<Clock>)
+            -> Result<DemC<T>, u8> where T: HasButtons + HasJoysticks + HasTriggers + HasPovs {
         let arc_controller = Arc::new(controller);
 
-        let (tx_command, rx_command) = mpsc::channel();
+        let (tx_command, rx_command) = mpsc::channel::<Vec<TimedInput>>();
+
+        let listener_constraints = constraints.clone();
 
         // Spawn a command listener
+        // It sleeps until either a command comes in or its next event is due, and does everything on this one thread
         let arc_controller_command_handler = arc_controller.clone();
+        let listener_clock = clock.clone();
+        let mode = Arc::new(Mutex::new(InputMode::Anarchy));
+        let listener_mode = mode.clone();
+        let ballot = Arc::new(Mutex::new(Ballot::new(DEFAULT_VOTE_WINDOW)));
+        let listener_ballot = ballot.clone();
+        let listener_guard = ListenerGuard::new(clock.clone());
         let command_listener = thread::spawn(move || {
-            //@todo these mutexes owning nothing is indicative of unrustic code
-            //@todo size according to number of buttons
-            let button_guards = Arc::new(
-                                    vec![Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()),
-                                         Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()),
-                                         Mutex::new(()), Mutex::new(()), Mutex::new(()), Mutex::new(()),
-                                         Mutex::new(()), Mutex::new(())]);
-        
-            let mut queued_commands: Vec<TimedInput> = Vec::new();
-            let mut active_joystick_commands: Vec<TimedInput> = Vec::new();
-            // There is no active button commands vector because closures
-            
+            let _listener_guard = listener_guard;
+            let constraints = listener_constraints;
+            let controller = arc_controller_command_handler;
+            let clock = listener_clock;
+            let mode = listener_mode;
+            let ballot = listener_ballot;
+
+            let mut scheduler = Scheduler::new(clock.now(), frames_per_second);
+            let mut next_analog_id: u64 = 0;
+            // Analog commands counting towards their elements' averages, by id
+            let mut active_analog_commands: HashMap<u64, AnalogCommand> = HashMap::new();
+            // Buttons in a press-release cycle
+            let mut busy_buttons: HashSet<String> = HashSet::new();
+            let mut disconnected = false;
+
             loop {
-                // Get all commands from the mpsc receiver
-                loop {
-                    match rx_command.try_recv() {
-                        Ok(command) => {
-                            queued_commands.push(command);
+                // Wait for the next command, or until the next event is due
+                let received = match scheduler.get_next_event_time() {
+                    // Once the DemC is gone, there's nothing left to wait for but events
+                    Some(next_time) if disconnected => {
+                        clock.sleep_until(next_time);
+                        Err(mpsc::RecvTimeoutError::Timeout)
+                    },
+                    None if disconnected => break,
+                    next_time => clock.recv_until(&rx_command, next_time)
+                };
+
+                let mut lines = Vec::new();
+                match received {
+                    Ok(commands) => { lines.push(commands); },
+                    // Once the DemC is gone, play out whatever is left and stop
+                    Err(mpsc::RecvTimeoutError::Disconnected) => { disconnected = true; },
+                    Err(mpsc::RecvTimeoutError::Timeout) => ()
+                }
+                while let Ok(commands) = rx_command.try_recv() {
+                    lines.push(commands);
+                }
+
+                // In anarchy, act on every line; in democracy, count each as a vote, opening a ballot if need be
+                for commands in lines.into_iter() {
+                    match *mode.lock().unwrap() {
+                        InputMode::Anarchy => {
+                            for command in commands.into_iter() {
+                                let start_time = command.start_time;
+                                schedule_command(&mut scheduler, &mut next_analog_id, start_time, command);
+                            }
                         },
-                        _ => { break; }
+                        InputMode::Democracy => {
+                            let mut ballot = ballot.lock().unwrap();
+                            if !ballot.is_open() {
+                                let (number, closing_time) = ballot.open(clock.now());
+                                scheduler.schedule(closing_time, Event::CloseBallot(number));
+                            }
+                            ballot.cast(commands);
+                        }
                     }
                 }
 
-                let time_now = time::get_time();
-
-                // Move all queued joystick commands whose time it is into the active joystick command list
-                // Try acting on all queued button commands whose time it is
-                let mut queued_commands_fresh: Vec<TimedInput> = Vec::new();
-                for command in queued_commands.iter() {
-                    if command.start_time <= time_now {
-                        match command.command.clone() {
-                            virtc::Input::Joystick(_, _, _) => {
-                                active_joystick_commands.push(command.clone());
+                // Act on every event that's due, then bring each analog element that changed in the meantime up to
+                // date once
+                let mut changed_elements: Vec<virtc::Input> = Vec::new();
+                while let Some((event_time, event)) = scheduler.pop_due_event(clock.now()) {
+                    match event {
+                        Event::AnalogStart(id, input, user) => {
+                            if !changed_elements.iter().any(|element| is_same_element(element, &input)) {
+                                changed_elements.push(input.clone());
+                            }
+                            active_analog_commands.insert(id, AnalogCommand { input: input,
+                                                                              user: user,
+                                                                              start_time: event_time,
+                                                                              id: id });
+                        },
+                        Event::AnalogEnd(id) => {
+                            if let Some(command) = active_analog_commands.remove(&id) {
+                                if !changed_elements.iter().any(|element| is_same_element(element, &command.input)) {
+                                    changed_elements.push(command.input);
+                                }
                             }
-                            virtc::Input::Button(name, _) => {
-                                // Is a button in a press-release cycle? If so, ignore vote
-                                // Otherwise, hold the button for as long as the command specified,
-                                // then release it for a frame before relinquishing control
-
-                                
-                                // Make sure that pressing this button would not complete an illegal combination
-                                let mut ignore_button = false;
-                                for &(ref constrained_button, ref constraining_buttons) in constraints.illegal_combinations.iter() {
-                                    
-                                    if constrained_button.as_ref() == name {
-                                        let mut constrained_button_in_use_count = 0;
-                                        for constraining_button in constraining_buttons.iter() {
-                                            let index = get_button_guard_index_gcn(&constraining_button);
-                                            if !{ button_guards[index].try_lock().is_ok() } {
-                                                constrained_button_in_use_count = constrained_button_in_use_count+1;
-                                            }
-                                        }
-                                        if constrained_button_in_use_count == constraining_buttons.len() {
-                                            ignore_button = true;
-                                        }
+                        },
+                        Event::Press(name, duration) => {
+                            // Is a button in a press-release cycle, or not on the controller at all? If so, ignore
+                            // vote. Otherwise, hold the button for as long as the command specified, then release it
+                            // for a frame before relinquishing control
+                            if busy_buttons.contains(&name) || controller.get_button_state(&name).is_none() {
+                                continue;
+                            }
+
+                            // Make sure that pressing this button would not complete an illegal combination
+                            let mut ignore_button = false;
+                            let illegal_combinations = constraints.illegal_combinations.iter();
+                            for &(ref constrained_button, ref constraining_buttons) in illegal_combinations {
+                                if *constrained_button == name {
+                                    let constrained_button_in_use_count = constraining_buttons.iter().filter(|button| {
+                                        controller.get_button_state(button) == Some(true)
+                                    }).count();
+                                    if constrained_button_in_use_count == constraining_buttons.len() {
+                                        ignore_button = true;
                                     }
                                 }
+                            }
 
-                                if !ignore_button {
-                                    let closure_controller = arc_controller_command_handler.clone();
-                                    let closure_button_name = name.clone();
-                                    
-                                    let command_clone = command.clone();
-                                    let button_guards_clone = button_guards.clone(); // Arc<Vec<Mutex<()>>>
-                                    
-                                    thread::spawn(move || {
-                                        let button_guard_index = get_button_guard_index_gcn(&name);
-                                        let button_guard_vec: &Vec<_> = button_guards_clone.deref();
-                                        let button_guard = &button_guard_vec[button_guard_index];
-                                        let lock_result = button_guard.try_lock();
-                                        match lock_result {
-                                            Ok(_) => {
-                                                let command1 = virtc::Input::Button(closure_button_name.clone(), true);
-                                                closure_controller.set_input(&command1);
-                                                thread::sleep_ms(command_clone.duration.num_milliseconds() as u32);
-                                                let command2 = virtc::Input::Button(closure_button_name.clone(), false);
-                                                closure_controller.set_input(&command2);
-                                            },
-                                            _ => ()
-                                        }
-                                    }); 
+                            if !ignore_button {
+                                controller.set_input(&virtc::Input::Button(name.clone(), true));
+                                busy_buttons.insert(name.clone());
+                                scheduler.schedule(event_time + duration, Event::Release(name));
+                            }
+                        },
+                        Event::Release(name) => {
+                            controller.set_input(&virtc::Input::Button(name.clone(), false));
+                            let unlock_time = event_time + scheduler.get_frame_duration();
+                            scheduler.schedule(unlock_time, Event::Unlock(name));
+                        },
+                        Event::Unlock(name) => {
+                            busy_buttons.remove(&name);
+                        },
+                        Event::CloseBallot(number) => {
+                            // Play the winning line from now, keeping its commands' timing relative to each other
+                            let winner = ballot.lock().unwrap().close(number);
+                            if let Some(commands) = winner {
+                                let first_start_time = commands[0].start_time;
+                                for command in commands.into_iter() {
+                                    let start_time = event_time + (command.start_time - first_start_time);
+                                    schedule_command(&mut scheduler, &mut next_analog_id, start_time, command);
                                 }
                             }
                         }
-                    } else {
-                        queued_commands_fresh.push(command.clone());
                     }
                 }
-                queued_commands = queued_commands_fresh;
 
-                // Prune old commands from the active list
-                let mut active_joystick_commands_fresh: Vec<TimedInput> = Vec::new();
-                for command in active_joystick_commands.iter() {
-                    if command.start_time + command.duration > time_now {
-                        active_joystick_commands_fresh.push(command.clone());
-                    }
-                }
-                active_joystick_commands = active_joystick_commands_fresh;
-
-                if !active_joystick_commands.is_empty() {
-                    // Get the average joystick direction
-                    //@todo use f64 for sums?
-                    let mut jx_sum: f32 = 0.0;
-                    let mut jy_sum: f32 = 0.0;
-                    let mut num_j_commands: u16 = 0;
-                    let mut cx_sum: f32 = 0.0;
-                    let mut cy_sum: f32 = 0.0;
-                    let mut num_c_commands: u16 = 0;
-
-                    // Loop over all commands
-                    for command in active_joystick_commands.iter() {
-                        match command.command {
-                            virtc::Input::Joystick(ref name, direction, strength) => {
-                                if name == "control_stick" {
-                                    let direction_rad: f32 = (direction as f32) * std::f32::consts::PI / 180.0;
-                                    jx_sum += direction_rad.cos() * strength;
-                                    jy_sum += direction_rad.sin() * strength;
-                                    num_j_commands += 1;
-                                } else {
-                                    let direction_rad: f32 = (direction as f32) * std::f32::consts::PI / 180.0;
-                                    cx_sum += direction_rad.cos() * strength;
-                                    cy_sum += direction_rad.sin() * strength;
-                                    num_c_commands += 1;                                
-                                }
-                            },
-                            _ => panic!("How did something besides a joystick or cstick command get here?")
-                        }
-                    }
+                for element in changed_elements.iter() {
+                    let mut active_commands: Vec<&AnalogCommand> = active_analog_commands.values().filter(|command| {
+                        is_same_element(&command.input, element)
+                    }).collect();
+                    active_commands.sort_by(|a, b| (a.start_time, a.id).cmp(&(b.start_time, b.id)));
 
-                    let jx_avg = (jx_sum / num_j_commands as f32) as f32;
-                    let jy_avg = (jy_sum / num_j_commands as f32) as f32;
-                    let cx_avg = (cx_sum / num_c_commands as f32) as f32;
-                    let cy_avg = (cy_sum / num_c_commands as f32) as f32;
-                    
-                    let mut j_direction_avg_rad = jy_avg.atan2(jx_avg);
-                    if j_direction_avg_rad < 0.0 {
-                        j_direction_avg_rad = j_direction_avg_rad + ((2.0*std::f32::consts::PI) as f32);
-                    }
-                    let mut c_direction_avg_rad = cy_avg.atan2(cx_avg);
-                    if c_direction_avg_rad < 0.0 {
-                        c_direction_avg_rad = c_direction_avg_rad + ((2.0*std::f32::consts::PI) as f32);
-                    }
-                    
-                    let j_direction_avg = (j_direction_avg_rad * 180.0 / std::f32::consts::PI) as u16;
-                    let mut j_strength_avg: f32 = jx_avg.abs() + jy_avg.abs(); //@todo lazy, but... what we want?
-                    if j_strength_avg > 1.0 {
-                        j_strength_avg = 1.0;
-                    }
-                    let c_direction_avg = (c_direction_avg_rad * 180.0 / std::f32::consts::PI) as u16;
-                    let mut c_strength_avg: f32 = cx_avg.abs() + cy_avg.abs(); //@todo lazy, but... what we want?
-                    if c_strength_avg > 1.0 {
-                        c_strength_avg = 1.0;
-                    }
-                    
-                    let j_command = if num_j_commands > 0 {
-                        virtc::Input::Joystick(String::from("control_stick"), j_direction_avg, j_strength_avg)
-                    } else {
-                        virtc::Input::Joystick(String::from("control_stick"), 0, 0.0)
-                    };
-                    let c_command = if num_c_commands > 0 {
-                        virtc::Input::Joystick(String::from("c_stick"), c_direction_avg, c_strength_avg)
-                    } else {
-                        virtc::Input::Joystick(String::from("c_stick"), 0, 0.0)
+                    let aggregation = match *element {
+                        virtc::Input::Joystick(ref name, _, _) => {
+                            constraints.aggregations.get(name).cloned().unwrap_or(Aggregation::VectorMean)
+                        },
+                        _ => Aggregation::VectorMean
                     };
-                    arc_controller_command_handler.set_input(&j_command);
-                    arc_controller_command_handler.set_input(&c_command);
-                } else {
-                    let j_command = virtc::Input::Joystick(String::from("control_stick"), 0, 0.0);
-                    let c_command = virtc::Input::Joystick(String::from("c_stick"), 0, 0.0);
-                    arc_controller_command_handler.set_input(&j_command);
-                    arc_controller_command_handler.set_input(&c_command);
+                    controller.set_input(&aggregate_analog_inputs(element, &active_commands, aggregation));
                 }
-
-                thread::sleep_ms(1);
             }
         });
         
+        let rate_limiter = RateLimiter::new(RateLimitConfig::new(), clock.now());
         let my_clone = arc_controller.clone();
-        Ok( DemC { controller: arc_controller,
-                   re: make_virtc_regex(my_clone.deref()).unwrap(),
+        Ok( DemC { directions: my_clone.get_direction_map().clone(),
+                   aliases: my_clone.get_alias_map().clone(),
+                   pov_directions: my_clone.get_pov_direction_map().clone(),
+                   controller: arc_controller,
+                   constraints: constraints,
+                   vocabulary: Vocabulary::new(my_clone.deref()),
+                   milliseconds_per_frame: (get_frame_duration(frames_per_second).num_microseconds().unwrap() as f32
+                                            / 1000.0).round() as u32,
+                   clock: clock,
+                   mode: mode,
+                   ballot: ballot,
+                   meter: Mutex::new(Meter::new(MeterConfig::new())),
+                   rate_limiter: Mutex::new(rate_limiter),
                    tx_command: tx_command,
                    command_listener: command_listener } )
     }
 }
 
-fn make_virtc_joystick_regex<T>(controller: &T) -> Result<String, u8> where T: HasJoysticks {
-    let mut regex_string = String::new();
-    
-    regex_string.push_str( r"(?P<joystick>" );
-        // Optional: strength modifier
-        regex_string.push_str( r"(" );
-            regex_string.push_str( r"(?P<joystick_strength>" );
-                regex_string.push_str( r"[:digit:]+" );
-            regex_string.push_str( r")%\s*" );
-        regex_string.push_str( r")?" );
-            
-        // Mandatory: joystick & direction
-        regex_string.push_str( r"(?P<joystick_direction>" );
-            for (joystick_name, _) in controller.get_joystick_map() {
-                if joystick_name == "control_stick" {
-                    regex_string.push_str( r"up|down|left|right|" );
-                } else if joystick_name == "c_stick" {
-                    regex_string.push_str( r"cup|cdown|cleft|cright|" );
-                }
-            }
-            // remove last pipe
-            let new_len = regex_string.len() - 1;
-            regex_string.truncate(new_len);
-        regex_string.push_str( r")" );
-        
-        // Optional: duration modifier
-        regex_string.push_str( r"(\s*" );
-            regex_string.push_str( r"(?P<joystick_duration>" );
-                regex_string.push_str( r"[:digit:]+" );
-            regex_string.push_str( r")" );
-            regex_string.push_str( r"(?P<joystick_duration_units>" );
-                regex_string.push_str( r"s|ms");
-            regex_string.push_str( r")");
-        regex_string.push_str( r")?" );
-    regex_string.push_str( r")" );
-    
-    Ok(regex_string)
+// Schedule a command to start at the given time: a button press, or an analog command's start and end
+fn schedule_command(scheduler: &mut Scheduler, next_analog_id: &mut u64, start_time: Timespec, command: TimedInput) {
+    match command.command {
+        virtc::Input::Button(name, _) => {
+            scheduler.schedule(start_time, Event::Press(name, command.duration));
+        },
+        input => {
+            scheduler.schedule(start_time, Event::AnalogStart(*next_analog_id, input, command.user));
+            scheduler.schedule(start_time + command.duration, Event::AnalogEnd(*next_analog_id));
+            *next_analog_id += 1;
+        }
+    }
 }
 
-fn make_virtc_button_regex<T>(controller: &T) -> Result<String, u8> where T: HasButtons {
-    let mut regex_string = String::new();
-    
-    regex_string.push_str( r"(?P<button>" );
-        regex_string.push_str( r"(" );
-            regex_string.push_str( r"(?P<button_name>" );
-                for (button_name, _) in controller.get_button_map() {
-                    regex_string.push_str(button_name);
-                    regex_string.push_str(r"|");
-                }
-                // remove last pipe
-                let new_len = regex_string.len() - 1;
-                regex_string.truncate(new_len);
-            regex_string.push_str( r")" );
-            
-            regex_string.push_str( r"(" );
-                regex_string.push_str( r"\s*(?P<button_duration>" );
-                    regex_string.push_str( r"[:digit:]+" );
-                regex_string.push_str( r")" );
-                
-                regex_string.push_str( r"(?P<button_duration_units>" );
-                    regex_string.push_str( r"s|ms" );
-                regex_string.push_str( r")" );
-            regex_string.push_str( r")?" );
-        regex_string.push_str( r")" );
-    regex_string.push_str( r")" );
-    
-    Ok(regex_string)
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-fn make_virtc_delay_regex() -> Result<String, u8> {
-    let mut regex_string = String::new();
-
-    regex_string.push_str( r"(?P<delay>" );
-        regex_string.push_str( r"(" );
-            regex_string.push_str( r"\(" );
-            
-            regex_string.push_str( r"(?P<delay_duration>" );
-                regex_string.push_str( r"[:digit:]+" );
-            regex_string.push_str( r")" );
-            
-            regex_string.push_str( r"(?P<delay_duration_units>" );
-                regex_string.push_str( r"s|ms" );
-            regex_string.push_str( r")" );
-                
-            regex_string.push_str( r"\)" );
-        regex_string.push_str( r")" );
-    
-        regex_string.push_str( r"|" );
-        
-        regex_string.push_str( r"(?P<delay_hardcode>" );
-            regex_string.push_str( r"[\+!\.]" );
-        regex_string.push_str( r")" );
-    regex_string.push_str( r")" );
-    
-    Ok(regex_string)
-}
+    use time::{Timespec, Duration};
+    use toml;
+
+    use demc::{DemC, ChatInterfaced, ControllerConstraints};
+    use demc::parser::{Limit, ParseError};
+    use demc::recc::{RecC, InputTrace};
+    use demc::layout::{ControllerLayout, load_test_profile};
+    use demc::virtc::HasButtons;
+    use demc::clock::VirtualClock;
+    use demc::democracy::InputMode;
+
+    // DemC on a virtual clock, with 20ms frames so that every event lands on a whole millisecond
+    fn make_recorded_demc_with_layout(layout: &ControllerLayout, constraints: ControllerConstraints)
+                                      -> (DemC<RecC>, InputTrace, VirtualClock) {
+        let clock = VirtualClock::new(Timespec::new(1000, 0));
+        let controller = RecC::with_clock(layout, Arc::new(clock.clone()));
+        let trace = controller.get_trace();
+
+        let demc = DemC::with_clock(controller, constraints, 50, Arc::new(clock.clone())).unwrap();
+        trace.restart();
+
+        (demc, trace, clock)
+    }
+
+    fn make_recorded_demc(constraints: ControllerConstraints) -> (DemC<RecC>, InputTrace, VirtualClock) {
+        make_recorded_demc_with_layout(&load_test_profile("gcn"), constraints)
+    }
+
+    fn no_constraints() -> ControllerConstraints {
+        ControllerConstraints::new()
+    }
+
+    // An axis' history, with strengths rounded to two places
+    fn rounded_axis_history(trace: &InputTrace, name: &str) -> Vec<(i64, f32)> {
+        trace.get_axis_history(name).into_iter().map(|(time, value)| (time, (value * 100.0).round() / 100.0)).collect()
+    }
+
+    #[test]
+    fn test_button_sequence() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+
+        demc.handle_commands(&String::from("a b250ms")).unwrap();
+        clock.advance(Duration::seconds(1));
+
+        // b starts 3 frames after a ends, and its release is pushed back to the next frame boundary
+        trace.assert_button_pressed("a", 0, 500);
+        trace.assert_button_pressed("b", 560, 260);
+    }
+
+    #[test]
+    fn test_hour_of_chat_on_virtual_clock() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+
+        // A line every 5 seconds for an hour
+        for _ in 0..720 {
+            demc.handle_commands(&String::from("a b250ms")).unwrap();
+            clock.advance(Duration::seconds(5));
+        }
+
+        let a_presses: Vec<_> = (0..720).map(|i| (i*5000, Some(500))).collect();
+        let b_presses: Vec<_> = (0..720).map(|i| (i*5000 + 560, Some(260))).collect();
+        assert_eq!(trace.get_button_presses("a"), a_presses);
+        assert_eq!(trace.get_button_presses("b"), b_presses);
+    }
+
+    #[test]
+    fn test_democracy_plays_the_winning_line() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+        demc.set_mode(InputMode::Democracy);
+        demc.set_vote_window(1000);
+
+        demc.handle_commands(&String::from("b")).unwrap();
+        clock.advance(Duration::milliseconds(100));
+        demc.handle_commands(&String::from("a")).unwrap();
+        demc.handle_commands(&String::from("a500ms")).unwrap();
+        clock.advance(Duration::milliseconds(100));
+        assert_eq!(demc.get_tally(), vec![(String::from("a(500ms)"), 2), (String::from("b(500ms)"), 1)]);
+
+        // The ballot opened with the first vote, and only its winner is played when it closes
+        clock.advance(Duration::seconds(2));
+        assert_eq!(trace.get_button_presses("a"), vec![(1000, Some(500))]);
+        trace.assert_button_never_pressed("b");
+        assert!(demc.get_tally().is_empty());
+    }
+
+    #[test]
+    fn test_layout_aliases_and_directions() {
+        let layout_string = r#"
+            [controller]
+            name = "test"
+            axes = { x = "x", y = "y" }
+            joysticks = { stick = { x = "x", y = "y", directions = { north = 90, northwest = 135 } } }
+            buttons = { a = 1, start = { index = 2, max_duration = 500, aliases = ["st"] } }
+        "#;
+        let layout = ControllerLayout::from_toml(&layout_string.parse().unwrap()).unwrap();
+        let (demc, trace, clock) = make_recorded_demc_with_layout(&layout, ControllerConstraints {
+            max_durations: layout.max_durations.clone(),
+            ..ControllerConstraints::new() });
+
+        assert!(demc.handle_commands(&String::from("st 1s")).is_err());
+        demc.handle_commands(&String::from("northwest st")).unwrap();
+        clock.advance(Duration::seconds(1));
+
+        trace.assert_axis_reached("x", -0.707, 0.01);
+        trace.assert_button_pressed("start", 240, 500);
+    }
+
+    #[test]
+    fn test_n64_buttons_are_guarded() {
+        let layout = load_test_profile("n64");
+        let (demc, trace, clock) = make_recorded_demc_with_layout(&layout, no_constraints());
+
+        demc.handle_commands(&String::from("cup 500ms")).unwrap();
+        clock.advance(Duration::milliseconds(100));
+        // cup is still held, so this vote is ignored rather than cutting the first press short
+        demc.handle_commands(&String::from("cup 100ms")).unwrap();
+        clock.advance(Duration::milliseconds(600));
+
+        assert_eq!(trace.get_button_presses("cup"), vec![(0, Some(500))]);
+    }
+
+    #[test]
+    fn test_invalid_lines_are_rejected() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+
+        assert!(demc.handle_commands(&String::from("hahah")).is_err());
+        assert_eq!(demc.handle_commands(&String::from("a 40s")),
+                   Err(ParseError::LimitExceeded(0, Limit::Duration(String::from("a"), 40000, 5000))));
+        assert_eq!(demc.handle_commands(&String::from("(20s)(20s)a")),
+                   Err(ParseError::LimitExceeded(5, Limit::LineDuration(40000, 30000))));
+        clock.advance(Duration::milliseconds(100));
+
+        assert!(trace.get_transitions().is_empty());
+    }
+
+    #[test]
+    fn test_configured_constraints_are_enforced() {
+        let config_string = r#"
+            [constraints]
+            max_commands_per_line = 4
+            [constraints.inputs]
+            a = { max_repeats = 2 }
+            control_stick = { min_strength = 20, max_duration = 1000 }
+        "#;
+        let layout = load_test_profile("gcn");
+        let constraints = ControllerConstraints::from_toml(&config_string.parse().unwrap(), &layout).unwrap();
+        assert_eq!(constraints.max_durations.get("b"), Some(&30000));
+        // Without illegal combinations of its own, the config keeps GameCube's reset protection
+        assert_eq!(constraints.illegal_combinations.len(), 3);
+        let no_combinations: toml::Value = "[constraints]\nillegal_combinations = []".parse().unwrap();
+        assert!(ControllerConstraints::from_toml(&no_combinations, &layout).unwrap().illegal_combinations.is_empty());
+        // Misspelled inputs are caught rather than left to do nothing
+        let misspelled_input: toml::Value = "[constraints.inputs]\nstrat = { max_repeats = 1 }".parse().unwrap();
+        assert_eq!(ControllerConstraints::from_toml(&misspelled_input, &layout).err(), Some(5));
+        let misspelled_combination: toml::Value = "[constraints]\nillegal_combinations = [[\"strat\", \"b\"]]"
+                                                  .parse().unwrap();
+        assert_eq!(ControllerConstraints::from_toml(&misspelled_combination, &layout).err(), Some(4));
+        let button_aggregation: toml::Value = "[constraints.inputs]\na = { aggregation = \"most_recent\" }"
+                                              .parse().unwrap();
+        assert_eq!(ControllerConstraints::from_toml(&button_aggregation, &layout).err(), Some(5));
+        let (demc, _, _) = make_recorded_demc(constraints);
+
+        assert_eq!(demc.handle_commands(&String::from("aba a")),
+                   Err(ParseError::LimitExceeded(4, Limit::Repeats(String::from("a"), 2))));
+        assert_eq!(demc.handle_commands(&String::from("10%up")),
+                   Err(ParseError::LimitExceeded(0, Limit::MinStrength(String::from("up"), 10, 20))));
+        assert_eq!(demc.handle_commands(&String::from("left 2s")),
+                   Err(ParseError::LimitExceeded(0, Limit::Duration(String::from("left"), 2000, 1000))));
+        assert_eq!(demc.handle_commands(&String::from("a b x y z")),
+                   Err(ParseError::LimitExceeded(8, Limit::CommandCount(5, 4))));
+        assert!(demc.handle_commands(&String::from("20%up 1s ab")).is_ok());
+    }
 
-fn make_virtc_regex<T>(controller: &T) -> Result<Regex, u8> where T: HasButtons + HasJoysticks {
-    // Dynamically generate regex that will match all of the virtual controller's inputs. LOL
-    let mut regex_string = String::new();
-    
-    regex_string.push_str( r"\s*" );
-    regex_string.push_str( r"(" );
-        regex_string.push_str(make_virtc_joystick_regex(controller).unwrap().as_ref());
-        regex_string.push_str( r"|" );
-        regex_string.push_str(make_virtc_button_regex(controller).unwrap().as_ref());
-        regex_string.push_str( r"|" );
-        regex_string.push_str(make_virtc_delay_regex().unwrap().as_ref());
-    regex_string.push_str( r")" );
-    regex_string.push_str( r"\s*" );
-    
-    match Regex::new(&regex_string) {
-        Ok(re) => Ok(re),
-        Err(_) => Err(1)
-    }
-}
\ No newline at end of file
+    #[test]
+    fn test_illegal_combination_is_ignored() {
+        let (demc, trace, clock) = make_recorded_demc(ControllerConstraints {
+            illegal_combinations: vec![(String::from("start"), vec![String::from("b"), String::from("x")])],
+            ..ControllerConstraints::new() });
+
+        demc.handle_commands(&String::from("b 1s")).unwrap();
+        demc.handle_commands(&String::from("x 1s")).unwrap();
+        clock.advance(Duration::milliseconds(100));
+        assert_eq!(demc.get_controller().get_button_state(&String::from("b")), Some(true));
+        demc.handle_commands(&String::from("start")).unwrap();
+        clock.advance(Duration::milliseconds(1200));
+        assert_eq!(demc.get_controller().get_button_state(&String::from("b")), Some(false));
+
+        trace.assert_button_pressed("b", 0, 1000);
+        trace.assert_button_pressed("x", 0, 1000);
+        trace.assert_button_never_pressed("start");
+    }
+
+    #[test]
+    fn test_simultaneous_joystick_commands_are_averaged() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+
+        demc.handle_commands(&String::from("up 500ms")).unwrap();
+        demc.handle_commands(&String::from("left 500ms")).unwrap();
+        clock.advance(Duration::milliseconds(700));
+
+        // The mean of up and left points up-left, at the mean's length of 0.707, until both commands expire
+        assert_eq!(rounded_axis_history(&trace, "jx"), vec![(0, -0.5), (500, 0.0)]);
+        assert_eq!(rounded_axis_history(&trace, "jy"), vec![(0, 0.5), (500, 0.0)]);
+    }
+
+    #[test]
+    fn test_trigger_commands_are_averaged() {
+        let (demc, trace, clock) = make_recorded_demc(no_constraints());
+
+        demc.handle_commands(&String::from("50%l 500ms")).unwrap();
+        demc.handle_commands(&String::from("100%l 500ms")).unwrap();
+        clock.advance(Duration::milliseconds(700));
+
+        // Triggers span their whole axis: 75% pressed is halfway between center and fully positive
+        assert_eq!(rounded_axis_history(&trace, "lt"), vec![(0, 0.5), (500, -1.0)]);
+        // ...and a trigger command doesn't click the button of the same name
+        trace.assert_button_never_pressed("l");
+    }
+
+    #[test]
+    fn test_simultaneous_pov_commands_are_averaged() {
+        let layout_string = r#"
+            [controller]
+            name = "test"
+            povs = { hat = { pov = 1, directions = { hup = 90, hleft = 180, hright = 0 } } }
+        "#;
+        let layout = ControllerLayout::from_toml(&layout_string.parse().unwrap()).unwrap();
+        let (demc, trace, clock) = make_recorded_demc_with_layout(&layout, no_constraints());
+
+        demc.handle_commands(&String::from("hup 500ms")).unwrap();
+        demc.handle_commands(&String::from("hleft 500ms")).unwrap();
+        clock.advance(Duration::milliseconds(200));
+        demc.handle_commands(&String::from("hright 100ms")).unwrap();
+        clock.advance(Duration::milliseconds(500));
+
+        // hright cancels out hleft while it lasts, and the hat recenters once every command expires
+        assert_eq!(trace.get_pov_history("hat"), vec![(0, Some(135)), (200, Some(90)), (300, Some(135)), (500, None)]);
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use demc::virtc::{HasJoysticks, HasTriggers, HasButtons, HasPovs};
+
+
+// The controller command language, eg. "50%up 1s (250ms) a+b":
+// line     := command+
+// command  := number "%" (direction | trigger) duration?
+//           | (direction | pov_direction | button) duration?
+//           | "(" duration ")" | "+" | "!" | "."
+// duration := number ("ms" | "s")
+// Words are whatever the controller's layout calls its elements, and needn't be separated, so "ab" is "a" then "b"
+
+// One command, as written in chat
+// Words are kept as written; it's up to the caller to look up what they stand for
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    // (direction word, strength in percent, duration in milliseconds)
+    Joystick(String, Option<u32>, Option<u32>),
+    // (POV direction word, duration in milliseconds)
+    Pov(String, Option<u32>),
+    // (trigger name, strength in percent, duration in milliseconds)
+    Trigger(String, u32, Option<u32>),
+    // (button name or alias, duration in milliseconds)
+    Button(String, Option<u32>),
+    // "(250ms)": wait the given number of milliseconds after the previous command ends
+    Delay(u32),
+    // "+": wait a frame, without waiting for the previous command to end
+    Frame,
+    // "!": wait a frame after the previous command ends
+    FrameAfter,
+    // ".": wait a beat after the previous command ends
+    Dot
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedCommand {
+    // Byte offset of the command in the line
+    pub position: usize,
+    pub command: Command
+}
+
+// What the parser was looking for when it found something else
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expected {
+    Command,
+    UnitsOrPercent,
+    DirectionOrTrigger,
+    Duration,
+    ClosingParenthesis
+}
+
+// A limit a well-formed command may still break
+#[derive(Clone, Debug, PartialEq)]
+pub enum Limit {
+    // (strength given, in percent) Strengths go up to 100%
+    Strength(u32),
+    // (element as written, strength given, lowest strength allowed), in percent
+    MinStrength(String, u32, u32),
+    // (element as written, duration given, longest duration allowed), in milliseconds
+    Duration(String, u32, u32),
+    // (element as written, most times it may appear in a line)
+    Repeats(String, u32),
+    // (time the line's commands take to start, longest time allowed), in milliseconds
+    LineDuration(u32, u32),
+    // (commands in the line, most commands allowed)
+    CommandCount(u32, u32)
+}
+
+// Why a line isn't a valid list of commands
+// Positions are byte offsets in the line
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    // The line has no commands in it
+    Empty,
+    // The line has something that isn't a word, number or symbol of the command language at the given position,
+    // eg. the "h" in "hahah"
+    UnknownWord(usize),
+    // The line has the wrong kind of token at the given position, eg. "50 up" has "up" where "%" should be
+    Unexpected(usize, Expected),
+    // The command at the given position breaks a limit, eg. "a 40s" holds a for too long
+    LimitExceeded(usize, Limit)
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expected::Command => write!(f, "a command"),
+            Expected::UnitsOrPercent => write!(f, "\"ms\", \"s\" or \"%\""),
+            Expected::DirectionOrTrigger => write!(f, "a direction or trigger"),
+            Expected::Duration => write!(f, "a duration like 250ms"),
+            Expected::ClosingParenthesis => write!(f, "\")\"")
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::Strength(strength) => write!(f, "strength {}% is over 100%", strength),
+            Limit::MinStrength(ref element, strength, min_strength) => {
+                write!(f, "{} needs a strength of at least {}%, not {}%", element, min_strength, strength)
+            },
+            Limit::Duration(ref element, duration, max_duration) => {
+                write!(f, "{} can last at most {}ms, not {}ms", element, max_duration, duration)
+            },
+            Limit::Repeats(ref element, max_repeats) => {
+                write!(f, "{} can appear at most {} times in a line", element, max_repeats)
+            },
+            Limit::LineDuration(duration, max_duration) => {
+                write!(f, "commands can start at most {}ms into a line, not {}ms", max_duration, duration)
+            },
+            Limit::CommandCount(count, max_count) => {
+                write!(f, "a line can have at most {} commands, not {}", max_count, count)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Empty => write!(f, "no commands"),
+            ParseError::UnknownWord(position) => write!(f, "unknown command at character {}", position + 1),
+            ParseError::Unexpected(position, expected) => {
+                write!(f, "expected {} at character {}", expected, position + 1)
+            },
+            ParseError::LimitExceeded(position, ref limit) => write!(f, "{} at character {}", limit, position + 1)
+        }
+    }
+}
+
+
+// The words a controller's commands are made of
+pub struct Vocabulary {
+    directions: HashSet<String>,
+    pov_directions: HashSet<String>,
+    triggers: HashSet<String>,
+    // Button names and aliases
+    buttons: HashSet<String>,
+    words: HashSet<String>,
+    max_word_len: usize
+}
+
+impl Vocabulary {
+    pub fn new<T>(controller: &T) -> Self where T: HasJoysticks + HasTriggers + HasButtons + HasPovs {
+        let directions: HashSet<String> = controller.get_direction_map().keys().cloned().collect();
+        let pov_directions: HashSet<String> = controller.get_pov_direction_map().keys().cloned().collect();
+        let triggers: HashSet<String> = controller.get_trigger_map().keys().cloned().collect();
+        let buttons: HashSet<String> = controller.get_button_map().keys()
+                                                 .chain(controller.get_alias_map().keys()).cloned().collect();
+
+        let words: HashSet<String> = directions.iter().chain(pov_directions.iter())
+                                               .chain(triggers.iter())
+                                               .chain(buttons.iter()).cloned().collect();
+        let max_word_len = words.iter().map(|word| word.len()).max().unwrap_or(0);
+
+        Vocabulary { directions: directions,
+                     pov_directions: pov_directions,
+                     triggers: triggers,
+                     buttons: buttons,
+                     words: words,
+                     max_word_len: max_word_len }
+    }
+
+    // The longest word at the start of text, if any
+    // Matching the longest word means that eg. "cup" is never read as "c" followed by "up"
+    fn match_word<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let mut len = if text.len() < self.max_word_len { text.len() } else { self.max_word_len };
+        while len > 0 {
+            if text.is_char_boundary(len) && self.words.contains(&text[..len]) {
+                return Some(&text[..len]);
+            }
+            len -= 1;
+        }
+
+        None
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    // A number not directly followed by units
+    Number(u32),
+    // A number directly followed by units, in milliseconds
+    Duration(u32),
+    Percent,
+    Word(String),
+    OpenParenthesis,
+    CloseParenthesis,
+    Plus,
+    Bang,
+    Dot
+}
+
+// Whether text starts with the given unit, as a unit rather than the start of a longer word, as in "5start"
+// Words needn't be separated, so a word may follow the unit directly, as in "500msb"
+fn starts_with_unit(text: &str, unit: &str, vocabulary: &Vocabulary) -> bool {
+    text.starts_with(unit) && vocabulary.match_word(text).map_or(true, |word| word.len() <= unit.len())
+}
+
+// Split a line into (position, token) pairs
+fn tokenize(line: &str, vocabulary: &Vocabulary) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < line.len() {
+        let rest = &line[position..];
+        let c = rest.chars().next().unwrap();
+
+        if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
+        }
+
+        let (token, len) = match c {
+            '0'...'9' => {
+                let digits_len = rest.find(|c: char| !c.is_digit(10)).unwrap_or(rest.len());
+                // Absurdly long numbers saturate rather than overflow, and are caught by whatever limit applies
+                let number = rest[..digits_len].bytes().fold(0u32, |number, digit| {
+                    number.saturating_mul(10).saturating_add((digit - b'0') as u32)
+                });
+
+                let units = &rest[digits_len..];
+                if starts_with_unit(units, "ms", vocabulary) {
+                    (Token::Duration(number), digits_len + 2)
+                } else if starts_with_unit(units, "s", vocabulary) {
+                    (Token::Duration(number.saturating_mul(1000)), digits_len + 1)
+                } else {
+                    (Token::Number(number), digits_len)
+                }
+            },
+            '%' => (Token::Percent, 1),
+            '(' => (Token::OpenParenthesis, 1),
+            ')' => (Token::CloseParenthesis, 1),
+            '+' => (Token::Plus, 1),
+            '!' => (Token::Bang, 1),
+            '.' => (Token::Dot, 1),
+            _ => match vocabulary.match_word(rest) {
+                Some(word) => (Token::Word(String::from(word)), word.len()),
+                None => return Err(ParseError::UnknownWord(position))
+            }
+        };
+
+        tokens.push((position, token));
+        position += len;
+    }
+
+    Ok(tokens)
+}
+
+// Parse a line into the commands it's made of
+pub fn parse(line: &str, vocabulary: &Vocabulary) -> Result<Vec<ParsedCommand>, ParseError> {
+    let tokens = try!(tokenize(line, vocabulary));
+    if tokens.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    // Position of the token at the given index, or of the end of the line
+    let position_of = |index: usize| match tokens.get(index) {
+        Some(&(position, _)) => position,
+        None => line.len()
+    };
+
+    // Consume an optional duration following an element
+    let take_duration = |index: &mut usize| match tokens.get(*index) {
+        Some(&(_, Token::Duration(duration))) => {
+            *index += 1;
+            Some(duration)
+        },
+        _ => None
+    };
+
+    let mut commands = Vec::new();
+    let mut index = 0;
+
+    while index < tokens.len() {
+        let (position, ref token) = tokens[index];
+        index += 1;
+
+        let command = match *token {
+            // A strength modifier, which must be followed by a direction or trigger
+            Token::Number(strength) => {
+                match tokens.get(index) {
+                    Some(&(_, Token::Percent)) => { index += 1; },
+                    _ => return Err(ParseError::Unexpected(position_of(index), Expected::UnitsOrPercent))
+                }
+
+                match tokens.get(index) {
+                    Some(&(_, Token::Word(ref word))) if vocabulary.directions.contains(word) => {
+                        index += 1;
+                        Command::Joystick(word.clone(), Some(strength), take_duration(&mut index))
+                    },
+                    Some(&(_, Token::Word(ref word))) if vocabulary.triggers.contains(word) => {
+                        index += 1;
+                        Command::Trigger(word.clone(), strength, take_duration(&mut index))
+                    },
+                    _ => return Err(ParseError::Unexpected(position_of(index), Expected::DirectionOrTrigger))
+                }
+            },
+            Token::Word(ref word) => {
+                if vocabulary.directions.contains(word) {
+                    Command::Joystick(word.clone(), None, take_duration(&mut index))
+                } else if vocabulary.pov_directions.contains(word) {
+                    Command::Pov(word.clone(), take_duration(&mut index))
+                } else if vocabulary.buttons.contains(word) {
+                    Command::Button(word.clone(), take_duration(&mut index))
+                } else {
+                    // A trigger without a strength
+                    return Err(ParseError::Unexpected(position, Expected::Command));
+                }
+            },
+            Token::OpenParenthesis => {
+                let delay = match tokens.get(index) {
+                    Some(&(_, Token::Duration(delay))) => delay,
+                    _ => return Err(ParseError::Unexpected(position_of(index), Expected::Duration))
+                };
+                index += 1;
+
+                match tokens.get(index) {
+                    Some(&(_, Token::CloseParenthesis)) => { index += 1; },
+                    _ => return Err(ParseError::Unexpected(position_of(index), Expected::ClosingParenthesis))
+                }
+
+                Command::Delay(delay)
+            },
+            Token::Plus => Command::Frame,
+            Token::Bang => Command::FrameAfter,
+            Token::Dot => Command::Dot,
+            Token::Duration(_) | Token::Percent | Token::CloseParenthesis => {
+                return Err(ParseError::Unexpected(position, Expected::Command));
+            }
+        };
+
+        commands.push(ParsedCommand { position: position, command: command });
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use demc::parser::*;
+    use demc::recc::RecC;
+    use demc::layout::load_test_profile;
+
+    fn make_gcn_vocabulary() -> Vocabulary {
+        let layout = load_test_profile("gcn");
+        Vocabulary::new(&RecC::new(&layout))
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let vocabulary = make_gcn_vocabulary();
+
+        let commands: Vec<Command> = parse("50%up 1s cup(250ms)ab+ 30%l", &vocabulary).unwrap()
+                                         .into_iter().map(|parsed| parsed.command).collect();
+        assert_eq!(commands, vec![Command::Joystick(String::from("up"), Some(50), Some(1000)),
+                                  Command::Joystick(String::from("cup"), None, None),
+                                  Command::Delay(250),
+                                  Command::Button(String::from("a"), None),
+                                  Command::Button(String::from("b"), None),
+                                  Command::Frame,
+                                  Command::Trigger(String::from("l"), 30, None)]);
+
+        // Units can be followed directly by a word
+        let commands: Vec<Command> = parse("a500msb b1sa", &vocabulary).unwrap()
+                                         .into_iter().map(|parsed| parsed.command).collect();
+        assert_eq!(commands, vec![Command::Button(String::from("a"), Some(500)),
+                                  Command::Button(String::from("b"), None),
+                                  Command::Button(String::from("b"), Some(1000)),
+                                  Command::Button(String::from("a"), None)]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let vocabulary = make_gcn_vocabulary();
+
+        assert_eq!(parse("  ", &vocabulary), Err(ParseError::Empty));
+        assert_eq!(parse("hahah", &vocabulary), Err(ParseError::UnknownWord(0)));
+        assert_eq!(parse("a 40", &vocabulary), Err(ParseError::Unexpected(4, Expected::UnitsOrPercent)));
+        assert_eq!(parse("5start", &vocabulary), Err(ParseError::Unexpected(1, Expected::UnitsOrPercent)));
+        assert_eq!(parse("50%a", &vocabulary), Err(ParseError::Unexpected(3, Expected::DirectionOrTrigger)));
+        assert_eq!(parse("a (250ms", &vocabulary), Err(ParseError::Unexpected(8, Expected::ClosingParenthesis)));
+        assert_eq!(format!("{}", parse("a up?", &vocabulary).unwrap_err()), "unknown command at character 5");
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use toml;
+use time::{Timespec, Duration};
+
+use demc::layout::read_toml_file;
+
+
+// Length, in milliseconds, of the window budgets are given over, unless configured otherwise
+pub const DEFAULT_RATE_LIMIT_WINDOW: u32 = 30000;
+// Once this many users are being tracked, users whose budgets have refilled are forgotten
+const MAX_TRACKED_USERS: usize = 1024;
+
+// How much chat may use the controller, read from the [rate_limits] section of a TOML file; see tppm.toml.example
+// Budgets refill continuously over the window, and a missing budget is unlimited
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    // Length, in milliseconds, of the window budgets are given over
+    pub window: u32,
+    // Most lines each user may send per window
+    pub user_lines: Option<u32>,
+    // Most controller-milliseconds, summed over the durations of their commands, each user's lines may take per window
+    pub user_milliseconds: Option<u32>,
+    // Most lines, and controller-milliseconds, all users together may use per window
+    pub global_lines: Option<u32>,
+    pub global_milliseconds: Option<u32>
+}
+
+impl RateLimitConfig {
+    // No limits
+    pub fn new() -> Self {
+        RateLimitConfig { window: DEFAULT_RATE_LIMIT_WINDOW,
+                          user_lines: None,
+                          user_milliseconds: None,
+                          global_lines: None,
+                          global_milliseconds: None }
+    }
+
+    // Err(1): Unable to open config file
+    // Err(2): Unable to parse config file as TOML
+    // Err(3): [rate_limits] section malformed
+    pub fn from_config_file(path: &str) -> Result<Self, u8> {
+        let tree = try!(read_toml_file(path));
+        RateLimitConfig::from_toml(&tree)
+    }
+
+    // Err(3): [rate_limits] section malformed
+    pub fn from_toml(tree: &toml::Value) -> Result<Self, u8> {
+        let mut config = RateLimitConfig::new();
+
+        for &key in ["window", "user_lines", "user_milliseconds", "global_lines", "global_milliseconds"].iter() {
+            let limit = match tree.lookup(&format!("rate_limits.{}", key)) {
+                Some(limit_value) => match limit_value.as_integer() {
+                    Some(limit) if limit > 0 => limit as u32,
+                    _ => return Err(3)
+                },
+                None => continue
+            };
+
+            match key {
+                "window" => { config.window = limit; },
+                "user_lines" => { config.user_lines = Some(limit); },
+                "user_milliseconds" => { config.user_milliseconds = Some(limit); },
+                "global_lines" => { config.global_lines = Some(limit); },
+                _ => { config.global_milliseconds = Some(limit); }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Which budget a line would have overrun
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    UserLines,
+    UserMilliseconds,
+    GlobalLines,
+    GlobalMilliseconds
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::UserLines => write!(f, "too many lines from this user"),
+            Rejection::UserMilliseconds => write!(f, "too much controller time for this user"),
+            Rejection::GlobalLines => write!(f, "too many lines from chat"),
+            Rejection::GlobalMilliseconds => write!(f, "too much controller time for chat")
+        }
+    }
+}
+
+
+// A budget of capacity tokens, refilling at capacity tokens per window
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    tokens_per_millisecond: f64,
+    last_update: Timespec
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, window: u32, time_now: Timespec) -> Self {
+        TokenBucket { capacity: capacity as f64,
+                      tokens: capacity as f64,
+                      tokens_per_millisecond: capacity as f64 / window as f64,
+                      last_update: time_now }
+    }
+
+    fn refill(&mut self, time_now: Timespec) {
+        let elapsed = (time_now - self.last_update).num_milliseconds();
+        if elapsed > 0 {
+            self.tokens = (self.tokens + elapsed as f64 * self.tokens_per_millisecond).min(self.capacity);
+            self.last_update = time_now;
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+// Whether every given bucket, if any, could spend its amount
+fn can_afford(buckets: &mut [(Option<&mut TokenBucket>, f64, Rejection)], time_now: Timespec)
+        -> Result<(), Rejection> {
+    for &mut (ref mut bucket, amount, rejection) in buckets.iter_mut() {
+        if let Some(ref mut bucket) = *bucket {
+            bucket.refill(time_now);
+            if bucket.tokens < amount {
+                return Err(rejection);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A user's budgets, and when we last told them a line of theirs was dropped
+struct UserBudgets {
+    lines: Option<TokenBucket>,
+    milliseconds: Option<TokenBucket>,
+    last_told: Option<Timespec>
+}
+
+// Per-user and global token buckets for lines and controller-milliseconds
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: HashMap<String, UserBudgets>,
+    global_lines: Option<TokenBucket>,
+    global_milliseconds: Option<TokenBucket>
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, time_now: Timespec) -> Self {
+        RateLimiter { global_lines: config.global_lines.map(|lines| TokenBucket::new(lines, config.window, time_now)),
+                      global_milliseconds: config.global_milliseconds.map(|milliseconds| {
+                          TokenBucket::new(milliseconds, config.window, time_now)
+                      }),
+                      users: HashMap::new(),
+                      config: config }
+    }
+
+    // Spend a line, taking the given number of controller-milliseconds, from the user's and chat's budgets
+    // Nothing is spent unless every budget can afford it
+    // Err(Rejection): the budget the line would have overrun
+    pub fn admit(&mut self, user: &String, milliseconds: u32, time_now: Timespec) -> Result<(), Rejection> {
+        if self.users.len() >= MAX_TRACKED_USERS {
+            self.forget_idle_users(time_now);
+        }
+
+        let config = &self.config;
+        let user_budgets = self.users.entry(user.clone()).or_insert_with(|| {
+            UserBudgets { lines: config.user_lines.map(|lines| TokenBucket::new(lines, config.window, time_now)),
+                          milliseconds: config.user_milliseconds.map(|milliseconds| {
+                              TokenBucket::new(milliseconds, config.window, time_now)
+                          }),
+                          last_told: None }
+        });
+
+        let milliseconds = milliseconds as f64;
+        let mut buckets = [(user_budgets.lines.as_mut(), 1.0, Rejection::UserLines),
+                           (user_budgets.milliseconds.as_mut(), milliseconds, Rejection::UserMilliseconds),
+                           (self.global_lines.as_mut(), 1.0, Rejection::GlobalLines),
+                           (self.global_milliseconds.as_mut(), milliseconds, Rejection::GlobalMilliseconds)];
+        try!(can_afford(&mut buckets, time_now));
+
+        for &mut (ref mut bucket, amount, _) in buckets.iter_mut() {
+            if let Some(ref mut bucket) = *bucket {
+                bucket.tokens -= amount;
+            }
+        }
+        Ok(())
+    }
+
+    // Note that a line of the user's was dropped, and say whether to tell them so
+    // Only their first dropped line in each window is worth telling them about; answering every one would let a single
+    // user spend our own chat budget
+    pub fn note_rejection(&mut self, user: &String, time_now: Timespec) -> bool {
+        let window = Duration::milliseconds(self.config.window as i64);
+        let user_budgets = match self.users.get_mut(user) {
+            Some(user_budgets) => user_budgets,
+            None => return true
+        };
+
+        match user_budgets.last_told {
+            Some(last_told) if time_now - last_told < window => false,
+            _ => {
+                user_budgets.last_told = Some(time_now);
+                true
+            }
+        }
+    }
+
+    // Forget users whose budgets have refilled, and who we haven't told about a dropped line this window, who'd be no
+    // different from users we've never seen
+    fn forget_idle_users(&mut self, time_now: Timespec) {
+        let window = Duration::milliseconds(self.config.window as i64);
+        self.users.retain(|_, user_budgets| {
+            let mut idle = match user_budgets.last_told {
+                Some(last_told) => time_now - last_told >= window,
+                None => true
+            };
+            for bucket in [&mut user_budgets.lines, &mut user_budgets.milliseconds].iter_mut() {
+                if let Some(ref mut bucket) = **bucket {
+                    bucket.refill(time_now);
+                    idle = idle && bucket.is_full();
+                }
+            }
+            !idle
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::{Timespec, Duration};
+
+    use demc::ratelimit::{RateLimiter, RateLimitConfig, Rejection};
+
+    #[test]
+    fn test_budgets_are_spent_and_refilled() {
+        let config = RateLimitConfig { window: 10000,
+                                       user_lines: Some(2),
+                                       user_milliseconds: Some(3000),
+                                       global_lines: None,
+                                       global_milliseconds: Some(5000) };
+        let start = Timespec::new(0, 0);
+        let mut limiter = RateLimiter::new(config, start);
+        let (alice, bob, carol) = (String::from("alice"), String::from("bob"), String::from("carol"));
+
+        assert_eq!(limiter.admit(&alice, 500, start), Ok(()));
+        assert_eq!(limiter.admit(&alice, 2600, start), Err(Rejection::UserMilliseconds));
+        assert_eq!(limiter.admit(&alice, 500, start), Ok(()));
+        assert_eq!(limiter.admit(&alice, 500, start), Err(Rejection::UserLines));
+
+        // Every user has a budget of their own, but they all share chat's
+        assert_eq!(limiter.admit(&bob, 3000, start), Ok(()));
+        assert_eq!(limiter.admit(&carol, 1500, start), Err(Rejection::GlobalMilliseconds));
+        assert_eq!(limiter.admit(&carol, 1000, start), Ok(()));
+
+        // Half a window refills half of alice's budgets
+        assert_eq!(limiter.admit(&alice, 1000, start + Duration::seconds(5)), Ok(()));
+        assert_eq!(limiter.admit(&alice, 100, start + Duration::seconds(5)), Err(Rejection::UserLines));
+
+        // Only alice's first dropped line in each window is worth telling her about
+        assert!(limiter.note_rejection(&alice, start + Duration::seconds(5)));
+        assert!(!limiter.note_rejection(&alice, start + Duration::seconds(14)));
+        assert!(limiter.note_rejection(&carol, start + Duration::seconds(14)));
+        assert!(limiter.note_rejection(&alice, start + Duration::seconds(15)));
+    }
+}
@@ -0,0 +1,363 @@
+#![allow(unused_variables)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use time::Timespec;
+
+use demc::virtc::*;
+use demc::layout::ControllerLayout;
+use demc::clock::{Clock, RealClock};
+
+
+// A virtual joystick driver that accepts everything and does nothing
+// Its axes and buttons look like a fully-configured vJoy device's, so that any controller hardware map fits it
+pub struct NullDriver;
+
+impl VJoystickDriver for NullDriver {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn claim(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn release(&self) {}
+
+    fn get_axis_exists(&self, axis: u32) -> bool {
+        axis >= 0x30 && axis <= 0x37
+    }
+
+    fn get_axis_min(&self, axis: u32) -> Result<i64, ()> {
+        Ok(0)
+    }
+
+    fn get_axis_max(&self, axis: u32) -> Result<i64, ()> {
+        Ok(0x8000)
+    }
+
+    fn get_button_count(&self) -> u8 {
+        128
+    }
+
+    fn set_axis(&self, axis: u32, value: i64) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_button(&self, button: u8, value: bool) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn get_disc_pov_count(&self) -> u8 {
+        4
+    }
+
+    fn get_cont_pov_count(&self) -> u8 {
+        4
+    }
+
+    fn set_disc_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_cont_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+// A change in the state of one controller element
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transition {
+    Axis(String, f32),
+    Button(String, bool),
+    Pov(String, Option<u16>)
+}
+
+#[derive(Clone, Debug)]
+pub struct TimedTransition {
+    pub time: Timespec,
+    pub transition: Transition
+}
+
+struct Trace {
+    clock: Arc<Clock>,
+    start_time: Timespec,
+    transitions: Vec<TimedTransition>,
+    axis_states: HashMap<String, f32>,
+    button_states: HashMap<String, bool>,
+    pov_states: HashMap<String, Option<u16>>
+}
+
+// A shared handle to the transitions recorded by a RecC
+// Clone one out of the controller before handing the controller off to a DemC
+#[derive(Clone)]
+pub struct InputTrace(Arc<Mutex<Trace>>);
+
+impl InputTrace {
+    fn new(clock: Arc<Clock>) -> Self {
+        InputTrace(Arc::new(Mutex::new(Trace { start_time: clock.now(),
+                                               clock: clock,
+                                               transitions: Vec::new(),
+                                               axis_states: HashMap::new(),
+                                               button_states: HashMap::new(),
+                                               pov_states: HashMap::new() })))
+    }
+
+    // Record a transition, if the value differs from the last one recorded for that element
+    // Elements start out neutral, so centering an axis or releasing a button that was never touched isn't recorded
+    fn record(&self, transition: Transition) {
+        let mut trace = self.0.lock().unwrap();
+
+        let changed = match transition {
+            Transition::Axis(ref name, value) => {
+                let last = trace.axis_states.insert(name.clone(), value).unwrap_or(0.0);
+                last != value
+            },
+            Transition::Button(ref name, value) => {
+                let last = trace.button_states.insert(name.clone(), value).unwrap_or(false);
+                last != value
+            },
+            Transition::Pov(ref name, direction) => {
+                let last = trace.pov_states.insert(name.clone(), direction).unwrap_or(None);
+                last != direction
+            }
+        };
+
+        if changed {
+            let time = trace.clock.now();
+            trace.transitions.push(TimedTransition { time: time, transition: transition });
+        }
+    }
+
+    // Forget all recorded transitions and measure future ones from now
+    // Element states are kept, so the next transition of each element is still relative to its current value
+    pub fn restart(&self) {
+        let mut trace = self.0.lock().unwrap();
+        trace.start_time = trace.clock.now();
+        trace.transitions.clear();
+    }
+
+    pub fn get_transitions(&self) -> Vec<TimedTransition> {
+        self.0.lock().unwrap().transitions.clone()
+    }
+
+    // Every transition, as (milliseconds since the trace (re)started, transition) pairs
+    pub fn get_timeline(&self) -> Vec<(i64, Transition)> {
+        let trace = self.0.lock().unwrap();
+        trace.transitions.iter()
+                         .map(|t| ((t.time - trace.start_time).num_milliseconds(), t.transition.clone()))
+                         .collect()
+    }
+
+    // Every press of the button with given name, as (start, duration) pairs in milliseconds since the trace
+    // (re)started. A press that hasn't been released yet has no duration
+    pub fn get_button_presses(&self, name: &str) -> Vec<(i64, Option<i64>)> {
+        let mut presses = Vec::new();
+        let mut press_start = None;
+
+        for (time, transition) in self.get_timeline() {
+            match transition {
+                Transition::Button(ref button, true) if button == name => {
+                    press_start = Some(time);
+                },
+                Transition::Button(ref button, false) if button == name => {
+                    if let Some(start) = press_start {
+                        presses.push((start, Some(time - start)));
+                    }
+                    press_start = None;
+                },
+                _ => ()
+            }
+        }
+        if let Some(start) = press_start {
+            presses.push((start, None));
+        }
+
+        presses
+    }
+
+    // Every value the axis with given name took, as (milliseconds since the trace (re)started, strength) pairs
+    pub fn get_axis_history(&self, name: &str) -> Vec<(i64, f32)> {
+        self.get_timeline().into_iter().filter_map(|(time, transition)| match transition {
+            Transition::Axis(ref axis, value) if axis == name => Some((time, value)),
+            _ => None
+        }).collect()
+    }
+
+    // Every direction the POV with given name took, as (milliseconds since the trace (re)started, direction) pairs
+    pub fn get_pov_history(&self, name: &str) -> Vec<(i64, Option<u16>)> {
+        self.get_timeline().into_iter().filter_map(|(time, transition)| match transition {
+            Transition::Pov(ref pov, direction) if pov == name => Some((time, direction)),
+            _ => None
+        }).collect()
+    }
+
+    // Assert that the button with given name was pressed at start_ms and held for exactly duration_ms
+    pub fn assert_button_pressed(&self, name: &str, start_ms: i64, duration_ms: i64) {
+        let presses = self.get_button_presses(name);
+        let found = presses.iter().any(|&press| press == (start_ms, Some(duration_ms)));
+
+        assert!(found, "expected {} pressed at {}ms for {}ms, got presses {:?}", name, start_ms, duration_ms, presses);
+    }
+
+    pub fn assert_button_never_pressed(&self, name: &str) {
+        let presses = self.get_button_presses(name);
+        assert!(presses.is_empty(), "expected {} never pressed, got presses {:?}", name, presses);
+    }
+
+    // Assert that the axis with given name reached the given strength at some point, give or take tolerance
+    pub fn assert_axis_reached(&self, name: &str, strength: f32, tolerance: f32) {
+        let history = self.get_axis_history(name);
+        let found = history.iter().any(|&(_, value)| (value - strength).abs() <= tolerance);
+
+        assert!(found, "expected {} to reach {} (+/- {}), got history {:?}", name, strength, tolerance, history);
+    }
+}
+
+
+// A recording virtual controller
+// It accepts inputs like any other virtual controller, but drives a NullDriver and records every axis, button and POV
+// transition in an InputTrace instead
+pub struct RecC {
+    axes: HashMap<String, (u32, i64, i64)>,
+    joysticks: HashMap<String, (String, String)>,
+    directions: HashMap<String, (String, u16)>,
+    triggers: HashMap<String, String>,
+    buttons: HashMap<String, u8>,
+    aliases: HashMap<String, String>,
+    povs: HashMap<String, (u8, bool)>,
+    pov_directions: HashMap<String, (String, u16)>,
+    driver: NullDriver,
+    state: ControllerState,
+    trace: InputTrace
+}
+
+impl IsVirtualDevice for RecC {
+    fn get_driver(&self) -> &VJoystickDriver {
+        &self.driver
+    }
+
+    fn get_state(&self) -> &ControllerState {
+        &self.state
+    }
+}
+impl HasAxes for RecC {
+    fn get_axis_map(&self) -> &HashMap<String, (u32, i64, i64)> {
+        &self.axes
+    }
+
+    // Err(1): strength argument invalid
+    // Err(2): axis not available
+    fn set_axis_state(&self, name: &String, strength: f32) -> Result<(), u8> {
+        if strength < -1.0 || strength > 1.0 {
+            return Err(1);
+        }
+        let (center, half_range) = match (self.get_axis_center(name), self.get_axis_max(name)) {
+            (Some(center), Some(max)) => (center, max - center),
+            _ => return Err(2)
+        };
+
+        self.state.set_axis(name, center + (strength * (half_range as f32)) as i64);
+        self.trace.record(Transition::Axis(name.clone(), strength));
+        Ok(())
+    }
+}
+impl HasJoysticks for RecC {
+    fn get_joystick_map(&self) -> &HashMap<String, (String, String)> {
+        &self.joysticks
+    }
+
+    fn get_direction_map(&self) -> &HashMap<String, (String, u16)> {
+        &self.directions
+    }
+}
+impl HasTriggers for RecC {
+    fn get_trigger_map(&self) -> &HashMap<String, String> {
+        &self.triggers
+    }
+}
+impl HasButtons for RecC {
+    fn get_button_map(&self) -> &HashMap<String, u8> {
+        &self.buttons
+    }
+
+    fn get_alias_map(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    // Err(1): button not available
+    fn set_button_state(&self, name: &String, value: bool) -> Result<(), u8> {
+        if !self.buttons.contains_key(name) {
+            return Err(1);
+        }
+
+        self.state.set_button(name, value);
+        self.trace.record(Transition::Button(name.clone(), value));
+        Ok(())
+    }
+}
+impl HasPovs for RecC {
+    fn get_pov_map(&self) -> &HashMap<String, (u8, bool)> {
+        &self.povs
+    }
+
+    fn get_pov_direction_map(&self) -> &HashMap<String, (String, u16)> {
+        &self.pov_directions
+    }
+
+    // Err(1): POV not available
+    fn set_pov_state(&self, name: &String, direction: Option<u16>) -> Result<(), u8> {
+        if !self.povs.contains_key(name) {
+            return Err(1);
+        }
+
+        self.state.set_pov(name, direction);
+        self.trace.record(Transition::Pov(name.clone(), direction));
+        Ok(())
+    }
+}
+impl HasAxesAndButtons for RecC {}
+impl AcceptsInputs for RecC {
+    fn set_input(&self, input: &Input) -> Result<(), u8> {
+        match input.clone() {
+            Input::Joystick(name, direction, strength) => self.set_joystick_state(&name, direction, strength),
+            Input::Button(name, value) => self.set_button_state(&name, value),
+            Input::Pov(name, direction) => self.set_pov_state(&name, direction),
+            Input::Trigger(name, strength) => self.set_trigger_state(&name, strength)
+        }
+    }
+}
+
+impl RecC {
+    pub fn new(layout: &ControllerLayout) -> Self {
+        RecC::with_clock(layout, Arc::new(RealClock))
+    }
+
+    // Make a recording controller that timestamps transitions with the given clock
+    // Its triggers start out released
+    pub fn with_clock(layout: &ControllerLayout, clock: Arc<Clock>) -> Self {
+        let recc = RecC { axes: layout.get_axis_map(&NullDriver).unwrap(),
+                          joysticks: layout.joysticks.clone(),
+                          directions: layout.directions.clone(),
+                          triggers: layout.triggers.clone(),
+                          buttons: layout.buttons.clone(),
+                          aliases: layout.aliases.clone(),
+                          povs: layout.povs.clone(),
+                          pov_directions: layout.pov_directions.clone(),
+                          driver: NullDriver,
+                          state: ControllerState::new(),
+                          trace: InputTrace::new(clock) };
+        recc.release_triggers().unwrap();
+        recc
+    }
+
+    pub fn get_trace(&self) -> InputTrace {
+        self.trace.clone()
+    }
+}
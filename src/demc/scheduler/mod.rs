@@ -0,0 +1,156 @@
+use std;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use time::{Timespec, Duration};
+
+use demc::virtc::Input;
+
+
+pub const DEFAULT_FRAMES_PER_SECOND: u32 = 60;
+
+// Something the command listener has to do at a given time
+#[derive(Clone)]
+pub enum Event {
+    // Start counting the analog (joystick, trigger or POV) command with given id, sent by the given user if known,
+    // towards its element's position
+    AnalogStart(u64, Input, Option<String>),
+    // Stop counting the analog command with given id
+    AnalogEnd(u64),
+    // Press the button with given name, and hold it for the given duration
+    Press(String, Duration),
+    Release(String),
+    // The button with given name has been released for a frame, and may be pressed again
+    Unlock(String),
+    // The democracy ballot with given number closes, and its winning line is played
+    CloseBallot(u64)
+}
+
+struct ScheduledEvent {
+    time: Timespec,
+    // Events due at the same time happen in the order they were scheduled
+    sequence: u64,
+    event: Event
+}
+
+// BinaryHeap is a max-heap, so order events latest first to pop the earliest
+impl Ord for ScheduledEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match other.time.cmp(&self.time) {
+            Ordering::Equal => other.sequence.cmp(&self.sequence),
+            ordering => ordering
+        }
+    }
+}
+impl PartialOrd for ScheduledEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for ScheduledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.time == other.time && self.sequence == other.sequence
+    }
+}
+impl Eq for ScheduledEvent {}
+
+
+// A priority queue of events, aligned to a frame clock
+// Every event happens on a frame boundary, counting frames from the scheduler's epoch, so that inputs change when the
+// emulated game polls them rather than at whatever moment a chat message happened to arrive
+pub struct Scheduler {
+    epoch: Timespec,
+    frame_duration: Duration,
+    events: BinaryHeap<ScheduledEvent>,
+    next_sequence: u64
+}
+
+impl Scheduler {
+    pub fn new(epoch: Timespec, frames_per_second: u32) -> Self {
+        Scheduler { epoch: epoch,
+                    frame_duration: get_frame_duration(frames_per_second),
+                    events: BinaryHeap::new(),
+                    next_sequence: 0 }
+    }
+
+    pub fn get_frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    // The first frame boundary at or after the given time
+    pub fn align_to_frame(&self, time: Timespec) -> Timespec {
+        let frame_ns = self.frame_duration.num_nanoseconds().unwrap();
+        let offset_ns = match (time - self.epoch).num_nanoseconds() {
+            Some(offset_ns) if offset_ns > 0 => offset_ns,
+            Some(_) => return self.epoch,
+            None => return time
+        };
+
+        let frames = (offset_ns + frame_ns - 1) / frame_ns;
+        self.epoch + Duration::nanoseconds(frames * frame_ns)
+    }
+
+    // Schedule an event for the first frame boundary at or after the given time
+    pub fn schedule(&mut self, time: Timespec, event: Event) {
+        let aligned_time = self.align_to_frame(time);
+        self.events.push(ScheduledEvent { time: aligned_time, sequence: self.next_sequence, event: event });
+        self.next_sequence += 1;
+    }
+
+    pub fn get_next_event_time(&self) -> Option<Timespec> {
+        self.events.peek().map(|scheduled| scheduled.time)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    // Take the earliest event, with the time it's scheduled for, if it's due by the given time
+    pub fn pop_due_event(&mut self, time_now: Timespec) -> Option<(Timespec, Event)> {
+        match self.get_next_event_time() {
+            Some(time) if time <= time_now => (),
+            _ => return None
+        }
+
+        self.events.pop().map(|scheduled| (scheduled.time, scheduled.event))
+    }
+}
+
+// The length of a frame, given a frame rate
+pub fn get_frame_duration(frames_per_second: u32) -> Duration {
+    Duration::nanoseconds(1000000000 / std::cmp::max(frames_per_second, 1) as i64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::{Timespec, Duration};
+
+    use demc::scheduler::{Scheduler, Event};
+
+    #[test]
+    fn test_events_are_frame_aligned_and_ordered() {
+        let epoch = Timespec::new(100, 0);
+        let mut scheduler = Scheduler::new(epoch, 50);
+
+        scheduler.schedule(epoch + Duration::milliseconds(45), Event::Release(String::from("b")));
+        scheduler.schedule(epoch + Duration::milliseconds(30), Event::Release(String::from("a")));
+        scheduler.schedule(epoch + Duration::milliseconds(40), Event::Release(String::from("c")));
+        scheduler.schedule(epoch - Duration::milliseconds(5), Event::Release(String::from("d")));
+
+        assert!(scheduler.pop_due_event(epoch - Duration::milliseconds(1)).is_none());
+        let mut events = Vec::new();
+        while let Some((time, event)) = scheduler.pop_due_event(epoch + Duration::seconds(1)) {
+            if let Event::Release(name) = event {
+                events.push(((time - epoch).num_milliseconds(), name));
+            }
+        }
+
+        // 20ms frames: events land on the first boundary at or after their time, in the order they were scheduled
+        assert_eq!(events, vec![(0, String::from("d")),
+                                (40, String::from("a")),
+                                (40, String::from("c")),
+                                (60, String::from("b"))]);
+        assert!(scheduler.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use demc::virtc::*;
+use demc::layout::ControllerLayout;
+
+
+// A virtual controller of any shape, as described by a ControllerLayout
+pub struct VGenC {
+    axes: HashMap<String, (u32, i64, i64)>,
+    joysticks: HashMap<String, (String, String)>,
+    directions: HashMap<String, (String, u16)>,
+    triggers: HashMap<String, String>,
+    buttons: HashMap<String, u8>,
+    aliases: HashMap<String, String>,
+    povs: HashMap<String, (u8, bool)>,
+    pov_directions: HashMap<String, (String, u16)>,
+    driver: Box<VJoystickDriver>,
+    state: ControllerState
+}
+
+impl IsVirtualDevice for VGenC {
+    fn get_driver(&self) -> &VJoystickDriver {
+        &*self.driver
+    }
+
+    fn get_state(&self) -> &ControllerState {
+        &self.state
+    }
+}
+impl HasAxes for VGenC {
+    fn get_axis_map(&self) -> &HashMap<String, (u32, i64, i64)> {
+        &self.axes
+    }
+}
+impl HasJoysticks for VGenC {
+    fn get_joystick_map(&self) -> &HashMap<String, (String, String)> {
+        &self.joysticks
+    }
+
+    fn get_direction_map(&self) -> &HashMap<String, (String, u16)> {
+        &self.directions
+    }
+}
+impl HasTriggers for VGenC {
+    fn get_trigger_map(&self) -> &HashMap<String, String> {
+        &self.triggers
+    }
+}
+impl HasButtons for VGenC {
+    fn get_button_map(&self) -> &HashMap<String, u8> {
+        &self.buttons
+    }
+
+    fn get_alias_map(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+}
+impl HasPovs for VGenC {
+    fn get_pov_map(&self) -> &HashMap<String, (u8, bool)> {
+        &self.povs
+    }
+
+    fn get_pov_direction_map(&self) -> &HashMap<String, (String, u16)> {
+        &self.pov_directions
+    }
+}
+impl HasAxesAndButtons for VGenC {}
+impl AcceptsInputs for VGenC {
+    fn set_input(&self, input: &Input) -> Result<(), u8> {
+        match input.clone() {
+            Input::Joystick(name, direction, strength) => self.set_joystick_state(&name, direction, strength),
+            Input::Button(name, value) => self.set_button_state(&name, value),
+            Input::Pov(name, direction) => self.set_pov_state(&name, direction),
+            Input::Trigger(name, strength) => self.set_trigger_state(&name, strength)
+        }
+    }
+}
+
+impl VGenC {
+    // Err(1): unable to get axis ranges from the driver
+    // Err(2): vjoystick doesn't meet the layout's requirements
+    // Err(3): unable to claim and reset vjoystick, or to release its triggers
+    pub fn new(driver: Box<VJoystickDriver>, layout: &ControllerLayout) -> Result<Self, u8> {
+        let axes = match layout.get_axis_map(&*driver) {
+            Ok(axes) => axes,
+            Err(_) => return Err(1)
+        };
+
+        let virtc = VGenC { axes: axes,
+                            joysticks: layout.joysticks.clone(),
+                            directions: layout.directions.clone(),
+                            triggers: layout.triggers.clone(),
+                            buttons: layout.buttons.clone(),
+                            aliases: layout.aliases.clone(),
+                            povs: layout.povs.clone(),
+                            pov_directions: layout.pov_directions.clone(),
+                            driver: driver,
+                            state: ControllerState::new() };
+
+        match virtc.verify_vjoystick_compatibility() {
+            Ok(_) => (),
+            Err(_) => return Err(2)
+        }
+        match virtc.verify_vjoystick_pov_compatibility() {
+            Ok(_) => (),
+            Err(_) => return Err(2)
+        }
+
+        match virtc.claim_and_reset() {
+            Ok(_) => (),
+            Err(_) => return Err(3)
+        }
+        match virtc.release_triggers() {
+            Ok(_) => Ok(virtc),
+            Err(_) => Err(3)
+        }
+    }
+}
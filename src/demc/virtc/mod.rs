@@ -2,41 +2,149 @@
 #![allow(unused_variables)]
 
 // Dependencies
+#[cfg(target_os = "windows")]
 pub mod vjoy_rust;
+#[cfg(target_os = "linux")]
+pub mod uinput_rust;
 
 extern crate std;
 
 use std::collections::HashMap;
+use std::sync::Mutex;
+
+
+// VJoystickDriver is a backend capable of presenting one virtual joystick to the system
+// Axes are identified by vJoy axis HID constants (0x30 through 0x37) and buttons by one-based index, whatever the
+// backend, so that controller hardware maps work unchanged across drivers
+pub trait VJoystickDriver: Send + Sync {
+    // Whether the backend is installed and usable at all
+    fn is_enabled(&self) -> bool;
+
+    fn claim(&self) -> Result<(), &'static str>;
+    fn reset(&self) -> Result<(), ()>;
+    fn release(&self);
+
+    fn get_axis_exists(&self, axis: u32) -> bool;
+    fn get_axis_min(&self, axis: u32) -> Result<i64, ()>;
+    fn get_axis_max(&self, axis: u32) -> Result<i64, ()>;
+    fn get_button_count(&self) -> u8;
+
+    fn set_axis(&self, axis: u32, value: i64) -> Result<(), ()>;
+    fn set_button(&self, button: u8, value: bool) -> Result<(), ()>;
+
+    // POV hats are identified by one-based index, and come in discrete and continuous flavors
+    // Discrete POV values are -1 (centered) or 0 through 3 (north, east, south, west); continuous POV values are -1
+    // (centered) or hundredths of a degree clockwise from north
+    fn get_disc_pov_count(&self) -> u8;
+    fn get_cont_pov_count(&self) -> u8;
+    fn set_disc_pov(&self, pov: u8, value: i32) -> Result<(), ()>;
+    fn set_cont_pov(&self, pov: u8, value: i32) -> Result<(), ()>;
+}
+
+// Make the platform's native driver for the virtual joystick with the given number: vJoy on Windows, uinput on Linux
+#[cfg(target_os = "windows")]
+pub fn make_native_driver(device_number: u32) -> Box<VJoystickDriver> {
+    Box::new(vjoy_rust::VJoyDriver::new(device_number))
+}
+
+#[cfg(target_os = "linux")]
+pub fn make_native_driver(device_number: u32) -> Box<VJoystickDriver> {
+    Box::new(uinput_rust::UinputDriver::new(device_number))
+}
+
+// The last value written to each of a virtual controller's axes, buttons and POVs
+// Elements that haven't been written to since the controller was last reset are absent
+pub struct ControllerState {
+    axes: Mutex<HashMap<String, i64>>,
+    buttons: Mutex<HashMap<String, bool>>,
+    povs: Mutex<HashMap<String, Option<u16>>>
+}
+
+impl ControllerState {
+    pub fn new() -> Self {
+        ControllerState { axes: Mutex::new(HashMap::new()),
+                          buttons: Mutex::new(HashMap::new()),
+                          povs: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get_axis(&self, name: &String) -> Option<i64> {
+        self.axes.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set_axis(&self, name: &String, value: i64) {
+        self.axes.lock().unwrap().insert(name.clone(), value);
+    }
+
+    pub fn get_button(&self, name: &String) -> Option<bool> {
+        self.buttons.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set_button(&self, name: &String, value: bool) {
+        self.buttons.lock().unwrap().insert(name.clone(), value);
+    }
+
+    pub fn get_pov(&self, name: &String) -> Option<Option<u16>> {
+        self.povs.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set_pov(&self, name: &String, direction: Option<u16>) {
+        self.povs.lock().unwrap().insert(name.clone(), direction);
+    }
+
+    // Snapshots of every axis, button and POV written to since the last reset
+    pub fn get_axes(&self) -> HashMap<String, i64> {
+        self.axes.lock().unwrap().clone()
+    }
+
+    pub fn get_buttons(&self) -> HashMap<String, bool> {
+        self.buttons.lock().unwrap().clone()
+    }
+
+    pub fn get_povs(&self) -> HashMap<String, Option<u16>> {
+        self.povs.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.axes.lock().unwrap().clear();
+        self.buttons.lock().unwrap().clear();
+        self.povs.lock().unwrap().clear();
+    }
+}
 
+// IsVirtualDevice says that the implementor is a representation of a virtual joystick, driven by some VJoystickDriver
+pub trait IsVirtualDevice {
+    fn get_driver(&self) -> &VJoystickDriver;
 
-// IsVJoyDevice says that the implementor is a representation of a vJoy virtual joystick
-pub trait IsVJoyDevice {
-    fn get_device_number(&self) -> u32;
+    // The state we last put the virtual joystick in; the driver is never consulted for it
+    fn get_state(&self) -> &ControllerState;
 
-    // Convenience function for claiming and resetting the vJoy device with the given number
-    // Err(1): vJoy isn't enabled
+    // Convenience function for claiming and resetting the virtual joystick
+    // Err(1): virtual joystick driver isn't enabled
     // Err(2): Unable to claim vjoystick
     // Err(3): Unable to reset vjoystick
     fn claim_and_reset(&self) -> Result<(), u8> {
-        if vjoy_rust::is_vjoy_enabled() == false {
+        if self.get_driver().is_enabled() == false {
             return Err(1);
         }
 
-        match vjoy_rust::claim_vjoystick(self.get_device_number()) {
+        match self.get_driver().claim() {
             Err(msg) => return Err(2),
             _ => ()
         }
 
-        match vjoy_rust::reset_vjoystick(self.get_device_number()) {
+        match self.get_driver().reset() {
             Err(_) => return Err(3),
-            _ => Ok(())
+            _ => ()
         }
+
+        self.get_state().clear();
+        Ok(())
     }
 }
 
 // HasAxes says that the implementor contains at least one vJoy virtual axis
 //@todo separate mins and maxes into their own maps and initialize those locally so that user libs don't need to
-pub trait HasAxes: IsVJoyDevice {
+pub trait HasAxes: IsVirtualDevice {
     // Map of axis names to (vJoy axis HID constant, minimum value, maximum value) triplets
     fn get_axis_map(&self) -> &HashMap<String, (u32, i64, i64)>;
 
@@ -64,10 +172,21 @@ pub trait HasAxes: IsVJoyDevice {
         }
     }
 
+    // Convenience function for getting the value of the axis with given name when centered
+    fn get_axis_center(&self, name: &String) -> Option<i64> {
+        match self.get_axis_map().get(name) {
+            Some(&(_, min, max)) => Some(min + (max - min)/2),
+            None => None
+        }
+    }
+
     // Get the current value of the axis with given name
-    //@todo
+    // Axes that haven't been set since the last reset are centered
     fn get_axis_state(&self, name: &String) -> Option<i64> {
-        return Some(0);
+        match self.get_state().get_axis(name) {
+            Some(value) => Some(value),
+            None => self.get_axis_center(name)
+        }
     }
 
     // Set the value of the axis with given name
@@ -95,18 +214,21 @@ pub trait HasAxes: IsVJoyDevice {
             None => return Err(3)
         };
 
-        let mid: i64 = ((max - min)/2) as i64;
-        let val = mid + (strength * (mid as f32)) as i64;
+        let half_range = (max - min)/2;
+        let val = min + half_range + (strength * (half_range as f32)) as i64;
 
-        match vjoy_rust::set_vjoystick_axis(self.get_device_number(), hid, val) {
-            Ok(_) => Ok(()),
+        match self.get_driver().set_axis(hid, val) {
+            Ok(_) => {
+                self.get_state().set_axis(name, val);
+                Ok(())
+            },
             Err(_) => Err(4)
         }
     }
 
     fn verify_vjoystick_axis_compatibility(&self) -> Result<(), ()> {
         for (_, &(axis_index, _, _)) in self.get_axis_map() {
-            if vjoy_rust::get_vjoystick_axis_exists(self.get_device_number(), axis_index)== false {
+            if self.get_driver().get_axis_exists(axis_index) == false {
                 return Err(());
             }
         }
@@ -120,6 +242,9 @@ pub trait HasJoysticks: HasAxes {
     // Map of joystick names to (axis, axis) tuples
     fn get_joystick_map(&self) -> &HashMap<String, (String, String)>;
 
+    // Map of direction words to (joystick name, direction in degrees) tuples
+    fn get_direction_map(&self) -> &HashMap<String, (String, u16)>;
+
     fn get_joystick_axis_names(&self, name: &String) -> Option<&(String, String)> {
         match self.get_joystick_map().get(name) {
             Some(tuple) => Some(tuple),
@@ -155,9 +280,73 @@ pub trait HasJoysticks: HasAxes {
     }
 }
 
-pub trait HasButtons: IsVJoyDevice {
+// HasTriggers says that the implementor has at least one trigger, a one-dimensional analog input that is one axis
+// Unlike joysticks, triggers rest at their axis' minimum rather than its center
+pub trait HasTriggers: HasAxes {
+    // Map of trigger names to axis names
+    fn get_trigger_map(&self) -> &HashMap<String, String>;
+
+    fn get_trigger_axis_name(&self, name: &String) -> Option<&String> {
+        self.get_trigger_map().get(name)
+    }
+
+    // Get the current strength of the trigger with given name, in the range [0.0, 1.0]
+    // Triggers that haven't been set since the last reset are released
+    fn get_trigger_state(&self, name: &String) -> Option<f32> {
+        let axis = match self.get_trigger_axis_name(name) {
+            Some(axis) => axis,
+            None => return None
+        };
+
+        match (self.get_state().get_axis(axis), self.get_axis_min(axis), self.get_axis_max(axis)) {
+            (Some(value), Some(min), Some(max)) if max > min => Some((value - min) as f32 / (max - min) as f32),
+            (None, Some(_), Some(_)) => Some(0.0),
+            _ => None
+        }
+    }
+
+    // Release every trigger, putting its axis at its minimum
+    // Resetting the driver centers every axis, so controllers with triggers release them after a reset
+    // Err(1): Unable to set a trigger's axis state
+    fn release_triggers(&self) -> Result<(), u8> {
+        for name in self.get_trigger_map().keys() {
+            match self.set_trigger_state(name, 0.0) {
+                Ok(()) => (),
+                Err(_) => return Err(1)
+            }
+        }
+
+        Ok(())
+    }
+
+    // Set the trigger state, given a strength in the range [0.0, 1.0]
+    // Err(1): Unable to find trigger with given name
+    // Err(2): strength argument invalid
+    // Err(3): Unable to set axis state
+    fn set_trigger_state(&self, trigger: &String, strength: f32) -> Result<(), u8> {
+        let axis = match self.get_trigger_axis_name(trigger) {
+            Some(axis) => axis.clone(),
+            None => return Err(1)
+        };
+
+        if strength < 0.0 || strength > 1.0 {
+            return Err(2);
+        }
+
+        // A released trigger is a fully negative axis, and a fully pressed one a fully positive axis
+        match self.set_axis_state(&axis, strength*2.0 - 1.0) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(3)
+        }
+    }
+}
+
+pub trait HasButtons: IsVirtualDevice {
     fn get_button_map(&self) -> &HashMap<String, u8>;
 
+    // Map of button aliases to the names of the buttons they stand for
+    fn get_alias_map(&self) -> &HashMap<String, String>;
+
     fn get_num_buttons(&self) -> usize {
         self.get_button_map().len()
     }
@@ -169,9 +358,17 @@ pub trait HasButtons: IsVJoyDevice {
         }
     }
     
-    //@todo
+    // Get the current value of the button with given name
+    // Buttons that haven't been set since the last reset are released
     fn get_button_state(&self, name: &String) -> Option<bool> {
-        return Some(false);
+        if !self.get_button_map().contains_key(name) {
+            return None;
+        }
+
+        match self.get_state().get_button(name) {
+            Some(value) => Some(value),
+            None => Some(false)
+        }
     }
 
     // Err(1): Unable to set virtual joystick button
@@ -179,16 +376,17 @@ pub trait HasButtons: IsVJoyDevice {
         //@todo unwrap here
         let index = self.get_button_index(name).unwrap();
 
-        let valc = value as i32;
-
-        match vjoy_rust::set_vjoystick_button(self.get_device_number(), index, valc) {
-            Ok(_) => Ok(()),
+        match self.get_driver().set_button(index, value) {
+            Ok(_) => {
+                self.get_state().set_button(name, value);
+                Ok(())
+            },
             Err(_) => Err(1)
         }
     }
 
     fn verify_vjoystick_button_compatibility(&self) -> Result<(), ()> {
-        if (vjoy_rust::get_vjoystick_button_count(self.get_device_number()) as usize) < self.get_button_map().len() {
+        if (self.get_driver().get_button_count() as usize) < self.get_button_map().len() {
             return Err(());
         }
         
@@ -196,6 +394,74 @@ pub trait HasButtons: IsVJoyDevice {
     }
 }
 
+// HasPovs says that the implementor has POV hats, eg. a D-pad that the emulator reads as a hat switch
+pub trait HasPovs: IsVirtualDevice {
+    // Map of POV names to (one-based POV number, whether the POV is continuous) tuples
+    fn get_pov_map(&self) -> &HashMap<String, (u8, bool)>;
+
+    // Map of direction words to (POV name, direction in degrees) tuples
+    fn get_pov_direction_map(&self) -> &HashMap<String, (String, u16)>;
+
+    // Get the current direction of the POV with given name, or None if it's centered
+    // POVs that haven't been set since the last reset are centered
+    fn get_pov_state(&self, name: &String) -> Option<Option<u16>> {
+        if !self.get_pov_map().contains_key(name) {
+            return None;
+        }
+
+        match self.get_state().get_pov(name) {
+            Some(direction) => Some(direction),
+            None => Some(None)
+        }
+    }
+
+    // Point the POV with given name in a direction, or center it with None
+    // Like joystick directions, POV directions are in degrees counterclockwise from right. Discrete POVs snap to the
+    // nearest of their four directions
+    // Err(1): Unable to find POV with given name
+    // Err(2): Unable to set POV
+    fn set_pov_state(&self, name: &String, direction: Option<u16>) -> Result<(), u8> {
+        let (pov, continuous) = match self.get_pov_map().get(name) {
+            Some(&(pov, continuous)) => (pov, continuous),
+            None => return Err(1)
+        };
+
+        // vJoy POVs count clockwise from north
+        let compass_direction = direction.map(|direction| (450 - (direction % 360) as i32) % 360);
+
+        let result = match (continuous, compass_direction) {
+            (true, Some(compass_direction)) => self.get_driver().set_cont_pov(pov, compass_direction * 100),
+            (false, Some(compass_direction)) => {
+                self.get_driver().set_disc_pov(pov, ((compass_direction + 45) % 360) / 90)
+            },
+            (true, None) => self.get_driver().set_cont_pov(pov, -1),
+            (false, None) => self.get_driver().set_disc_pov(pov, -1)
+        };
+
+        match result {
+            Ok(_) => {
+                self.get_state().set_pov(name, direction);
+                Ok(())
+            },
+            Err(_) => Err(2)
+        }
+    }
+
+    fn verify_vjoystick_pov_compatibility(&self) -> Result<(), ()> {
+        for (_, &(pov, continuous)) in self.get_pov_map() {
+            let pov_count = match continuous {
+                true => self.get_driver().get_cont_pov_count(),
+                false => self.get_driver().get_disc_pov_count()
+            };
+            if pov > pov_count {
+                return Err(());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub trait HasAxesAndButtons: HasAxes + HasButtons {
     fn verify_vjoystick_compatibility(&self) -> Result<(), ()> {
         match self.verify_vjoystick_axis_compatibility() {
@@ -213,8 +479,84 @@ pub trait HasAxesAndButtons: HasAxes + HasButtons {
 #[derive(Clone)]
 pub enum Input {
     Joystick(String, u16, f32),
-    Button(String, bool)
+    Button(String, bool),
+    Pov(String, Option<u16>),
+    Trigger(String, f32)
 }
 pub trait AcceptsInputs {
     fn set_input(&self, input: &Input) -> Result<(), u8>;
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use demc::recc::NullDriver;
+    use demc::virtc::{VJoystickDriver, ControllerState, IsVirtualDevice, HasAxes, HasTriggers};
+
+    // A controller whose axis doesn't start at zero, and is also a trigger
+    struct OffsetAxisController {
+        axes: HashMap<String, (u32, i64, i64)>,
+        triggers: HashMap<String, String>,
+        driver: NullDriver,
+        state: ControllerState
+    }
+
+    impl IsVirtualDevice for OffsetAxisController {
+        fn get_driver(&self) -> &VJoystickDriver {
+            &self.driver
+        }
+
+        fn get_state(&self) -> &ControllerState {
+            &self.state
+        }
+    }
+    impl HasAxes for OffsetAxisController {
+        fn get_axis_map(&self) -> &HashMap<String, (u32, i64, i64)> {
+            &self.axes
+        }
+    }
+    impl HasTriggers for OffsetAxisController {
+        fn get_trigger_map(&self) -> &HashMap<String, String> {
+            &self.triggers
+        }
+    }
+
+    fn make_offset_axis_controller() -> OffsetAxisController {
+        let mut axes = HashMap::new();
+        axes.insert(String::from("x"), (0x30, 1000, 3000));
+        let mut triggers = HashMap::new();
+        triggers.insert(String::from("l"), String::from("x"));
+        OffsetAxisController { axes: axes, triggers: triggers, driver: NullDriver, state: ControllerState::new() }
+    }
+
+    #[test]
+    fn test_axes_span_their_range_around_its_center() {
+        let controller = make_offset_axis_controller();
+        let x = String::from("x");
+
+        assert_eq!(controller.get_axis_center(&x), Some(2000));
+        assert_eq!(controller.get_axis_state(&x), Some(2000));
+        controller.set_axis_state(&x, -1.0).unwrap();
+        assert_eq!(controller.get_axis_state(&x), Some(1000));
+        controller.set_axis_state(&x, 0.5).unwrap();
+        assert_eq!(controller.get_axis_state(&x), Some(2500));
+        controller.set_axis_state(&x, 1.0).unwrap();
+        assert_eq!(controller.get_axis_state(&x), Some(3000));
+    }
+    #[test]
+    fn test_triggers_rest_at_their_axis_minimum() {
+        let controller = make_offset_axis_controller();
+        let l = String::from("l");
+
+        // A trigger that hasn't been touched since the reset is released, even though the reset centered its axis
+        assert_eq!(controller.get_trigger_state(&l), Some(0.0));
+        controller.release_triggers().unwrap();
+        assert_eq!(controller.get_axis_state(&String::from("x")), Some(1000));
+        assert_eq!(controller.get_trigger_state(&l), Some(0.0));
+
+        controller.set_trigger_state(&l, 1.0).unwrap();
+        assert_eq!(controller.get_trigger_state(&l), Some(1.0));
+    }
+}
@@ -0,0 +1,327 @@
+pub mod uinputinterface;
+
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::slice;
+use std::sync::Mutex;
+
+use demc::virtc::VJoystickDriver;
+
+// Rustifying uinput wrapper functions + convenience functions
+// Where vJoy devices are configured ahead of time by the user, uinput devices are created by us: every gamepad
+// exposes all eight vJoy axes, UINPUT_BUTTON_COUNT buttons and UINPUT_POV_COUNT hats, and comes into existence when
+// it's claimed
+// evdev hats are neither discrete nor continuous, so both kinds of POV drive the same hats, snapped to eight directions
+
+pub const UINPUT_AXIS_MIN: i64 = 0;
+pub const UINPUT_AXIS_MAX: i64 = 0x8000;
+pub const UINPUT_BUTTON_COUNT: u8 = 32;
+pub const UINPUT_POV_COUNT: u8 = 4;
+
+
+// Translate a vJoy axis HID usage constant (0x30 through 0x37) into an evdev absolute axis code
+fn get_abs_code(axis: u32) -> Option<u16> {
+    match axis {
+        0x30 => Some(uinputinterface::ABS_X),
+        0x31 => Some(uinputinterface::ABS_Y),
+        0x32 => Some(uinputinterface::ABS_Z),
+        0x33 => Some(uinputinterface::ABS_RX),
+        0x34 => Some(uinputinterface::ABS_RY),
+        0x35 => Some(uinputinterface::ABS_RZ),
+        0x36 => Some(uinputinterface::ABS_THROTTLE),
+        0x37 => Some(uinputinterface::ABS_RUDDER),
+        _ => None
+    }
+}
+
+// Translate a one-based POV number into the evdev absolute axis codes of a hat's x and y axes
+fn get_hat_codes(pov: u8) -> Option<(u16, u16)> {
+    match pov {
+        1...UINPUT_POV_COUNT => {
+            let hat_x = uinputinterface::ABS_HAT0X + 2*(pov as u16 - 1);
+            Some((hat_x, hat_x + 1))
+        },
+        _ => None
+    }
+}
+
+// Translate a continuous POV value into hat x and y values, each -1, 0 or 1. Hat y grows downwards
+fn get_hat_values(cont_pov: i32) -> (i32, i32) {
+    if cont_pov < 0 {
+        return (0, 0);
+    }
+
+    // Anything within 22.5 degrees of an axis' direction counts as that direction
+    let threshold = (22.5f32).to_radians().sin();
+    let direction_rad = (cont_pov as f32 / 100.0).to_radians();
+    let snap = |value: f32| if value > threshold { 1 } else if value < -threshold { -1 } else { 0 };
+
+    (snap(direction_rad.sin()), -snap(direction_rad.cos()))
+}
+
+// Translate a one-based vJoy button number into an evdev key code
+fn get_key_code(button: u8) -> Option<u16> {
+    match button {
+        1...16 => Some(uinputinterface::BTN_TRIGGER + (button as u16 - 1)),
+        17...UINPUT_BUTTON_COUNT => Some(uinputinterface::BTN_TRIGGER_HAPPY1 + (button as u16 - 17)),
+        _ => None
+    }
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+    }
+}
+
+fn write_event(file: &mut File, type_: u16, code: u16, value: i32) -> Result<(), ()> {
+    let event = uinputinterface::input_event {
+        time: uinputinterface::timeval { tv_sec: 0, tv_usec: 0 },
+        type_: type_,
+        code: code,
+        value: value
+    };
+
+    match file.write_all(as_bytes(&event)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(())
+    }
+}
+
+// Write an event followed by a synchronization report, so that readers see it immediately
+pub fn write_event_and_sync(file: &mut File, type_: u16, code: u16, value: i32) -> Result<(), ()> {
+    try!(write_event(file, type_, code, value));
+    write_event(file, uinputinterface::EV_SYN, uinputinterface::SYN_REPORT, 0)
+}
+
+fn ioctl_checked(file: &File, request: libc::c_ulong, value: libc::c_int) -> Result<(), ()> {
+    unsafe {
+        match uinputinterface::ioctl(file.as_raw_fd(), request, value) {
+            -1 => Err(()),
+            _ => Ok(())
+        }
+    }
+}
+
+// Open /dev/uinput and create a device on it that reports the given key codes and (absolute axis code, minimum,
+// maximum) axes
+pub fn create_uinput_device(name: &str, version: u16, key_codes: &[u16], abs_axes: &[(u16, i32, i32)])
+        -> Result<File, ()>
+{
+    let mut file = match OpenOptions::new().write(true).open(uinputinterface::UINPUT_PATH) {
+        Ok(file) => file,
+        Err(_) => return Err(())
+    };
+
+    let mut device = uinputinterface::uinput_user_dev::default();
+
+    if !key_codes.is_empty() {
+        try!(ioctl_checked(&file, uinputinterface::UI_SET_EVBIT, uinputinterface::EV_KEY as libc::c_int));
+        for &code in key_codes.iter() {
+            try!(ioctl_checked(&file, uinputinterface::UI_SET_KEYBIT, code as libc::c_int));
+        }
+    }
+
+    if !abs_axes.is_empty() {
+        try!(ioctl_checked(&file, uinputinterface::UI_SET_EVBIT, uinputinterface::EV_ABS as libc::c_int));
+        for &(code, min, max) in abs_axes.iter() {
+            try!(ioctl_checked(&file, uinputinterface::UI_SET_ABSBIT, code as libc::c_int));
+            device.absmin[code as usize] = min;
+            device.absmax[code as usize] = max;
+        }
+    }
+
+    for (dst, src) in device.name.iter_mut().zip(name.bytes().take(uinputinterface::UINPUT_MAX_NAME_SIZE - 1)) {
+        *dst = src as libc::c_char;
+    }
+    device.id.bustype = uinputinterface::BUS_VIRTUAL;
+    device.id.vendor = 0x1209;
+    device.id.product = 0x7770;
+    device.id.version = version;
+
+    match file.write_all(as_bytes(&device)) {
+        Ok(_) => (),
+        Err(_) => return Err(())
+    }
+
+    try!(ioctl_checked(&file, uinputinterface::UI_DEV_CREATE, 0));
+
+    Ok(file)
+}
+
+// Create a gamepad with every vJoy axis, UINPUT_BUTTON_COUNT buttons and UINPUT_POV_COUNT hats
+fn create_gamepad(index: u32) -> Result<File, ()> {
+    let key_codes: Vec<u16> = (1..(UINPUT_BUTTON_COUNT+1)).map(|button| get_key_code(button).unwrap()).collect();
+
+    let mut abs_axes: Vec<(u16, i32, i32)> = (0x30..0x38).map(|axis| {
+        (get_abs_code(axis).unwrap(), UINPUT_AXIS_MIN as i32, UINPUT_AXIS_MAX as i32)
+    }).collect();
+    for pov in 1..(UINPUT_POV_COUNT+1) {
+        let (hat_x, hat_y) = get_hat_codes(pov).unwrap();
+        abs_axes.push((hat_x, -1, 1));
+        abs_axes.push((hat_y, -1, 1));
+    }
+
+    create_uinput_device(&format!("TPPM virtual gamepad {}", index), index as u16, &key_codes, &abs_axes)
+}
+
+
+// Wrapper functions
+
+pub fn is_uinput_enabled() -> bool {
+    match OpenOptions::new().write(true).open(Path::new(uinputinterface::UINPUT_PATH)) {
+        Ok(_) => true,
+        Err(_) => false
+    }
+}
+
+
+// Driver
+
+// A VJoystickDriver backed by a uinput gamepad that we create on claim
+// Releasing the driver, or dropping it, destroys the device
+pub struct UinputDriver {
+    device_number: u32,
+    device: Mutex<Option<File>>
+}
+
+impl UinputDriver {
+    pub fn new(device_number: u32) -> Self {
+        UinputDriver { device_number: device_number, device: Mutex::new(None) }
+    }
+
+    // Write a batch of (type, code, value) events followed by a single synchronization report
+    fn write_events_and_sync(&self, events: &[(u16, u16, i32)]) -> Result<(), ()> {
+        let mut device = self.device.lock().unwrap();
+        let file = match *device {
+            Some(ref mut file) => file,
+            None => return Err(())
+        };
+
+        for &(type_, code, value) in events.iter() {
+            try!(write_event(file, type_, code, value));
+        }
+        write_event(file, uinputinterface::EV_SYN, uinputinterface::SYN_REPORT, 0)
+    }
+
+    fn set_hat(&self, pov: u8, (x, y): (i32, i32)) -> Result<(), ()> {
+        match get_hat_codes(pov) {
+            Some((hat_x, hat_y)) => self.write_events_and_sync(&[(uinputinterface::EV_ABS, hat_x, x),
+                                                                 (uinputinterface::EV_ABS, hat_y, y)]),
+            None => Err(())
+        }
+    }
+}
+
+impl VJoystickDriver for UinputDriver {
+    fn is_enabled(&self) -> bool {
+        is_uinput_enabled()
+    }
+
+    fn claim(&self) -> Result<(), &'static str> {
+        let mut device = self.device.lock().unwrap();
+
+        if device.is_some() {
+            // We've already claimed it
+            return Ok(());
+        }
+
+        match create_gamepad(self.device_number) {
+            Ok(file) => {
+                *device = Some(file);
+                Ok(())
+            },
+            Err(_) => Err("Unable to create uinput device; is the uinput module loaded and /dev/uinput writable?")
+        }
+    }
+
+    // Center every axis and hat, and release every button
+    fn reset(&self) -> Result<(), ()> {
+        let mut device = self.device.lock().unwrap();
+        let file = match *device {
+            Some(ref mut file) => file,
+            None => return Err(())
+        };
+
+        let mid = (UINPUT_AXIS_MIN + (UINPUT_AXIS_MAX - UINPUT_AXIS_MIN)/2) as i32;
+        for axis in 0x30..0x38 {
+            try!(write_event(file, uinputinterface::EV_ABS, get_abs_code(axis).unwrap(), mid));
+        }
+        for button in 1..(UINPUT_BUTTON_COUNT+1) {
+            try!(write_event(file, uinputinterface::EV_KEY, get_key_code(button).unwrap(), 0));
+        }
+        for pov in 1..(UINPUT_POV_COUNT+1) {
+            let (hat_x, hat_y) = get_hat_codes(pov).unwrap();
+            try!(write_event(file, uinputinterface::EV_ABS, hat_x, 0));
+            try!(write_event(file, uinputinterface::EV_ABS, hat_y, 0));
+        }
+
+        write_event(file, uinputinterface::EV_SYN, uinputinterface::SYN_REPORT, 0)
+    }
+
+    fn release(&self) {
+        if let Some(file) = self.device.lock().unwrap().take() {
+            ioctl_checked(&file, uinputinterface::UI_DEV_DESTROY, 0);
+        }
+    }
+
+    fn get_axis_exists(&self, axis: u32) -> bool {
+        get_abs_code(axis).is_some()
+    }
+
+    fn get_axis_min(&self, axis: u32) -> Result<i64, ()> {
+        match get_abs_code(axis) {
+            Some(_) => Ok(UINPUT_AXIS_MIN),
+            None => Err(())
+        }
+    }
+
+    fn get_axis_max(&self, axis: u32) -> Result<i64, ()> {
+        match get_abs_code(axis) {
+            Some(_) => Ok(UINPUT_AXIS_MAX),
+            None => Err(())
+        }
+    }
+
+    fn get_button_count(&self) -> u8 {
+        UINPUT_BUTTON_COUNT
+    }
+
+    fn set_axis(&self, axis: u32, value: i64) -> Result<(), ()> {
+        match get_abs_code(axis) {
+            Some(code) => self.write_events_and_sync(&[(uinputinterface::EV_ABS, code, value as i32)]),
+            None => Err(())
+        }
+    }
+
+    fn set_button(&self, button: u8, value: bool) -> Result<(), ()> {
+        match get_key_code(button) {
+            Some(code) => self.write_events_and_sync(&[(uinputinterface::EV_KEY, code, value as i32)]),
+            None => Err(())
+        }
+    }
+
+    fn get_disc_pov_count(&self) -> u8 {
+        UINPUT_POV_COUNT
+    }
+
+    fn get_cont_pov_count(&self) -> u8 {
+        UINPUT_POV_COUNT
+    }
+
+    fn set_disc_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        match value {
+            -1...3 => self.set_hat(pov, get_hat_values(if value < 0 { -1 } else { value * 9000 })),
+            _ => Err(())
+        }
+    }
+
+    fn set_cont_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        self.set_hat(pov, get_hat_values(value))
+    }
+}
@@ -0,0 +1,85 @@
+/* hand-written from linux/input.h, linux/input-event-codes.h and linux/uinput.h */
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+
+extern crate libc;
+
+pub const UINPUT_PATH: &'static str = "/dev/uinput";
+pub const UINPUT_MAX_NAME_SIZE: usize = 80;
+pub const ABS_CNT: usize = 0x40;
+
+pub const BUS_VIRTUAL: u16 = 0x06;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0x00;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_Z: u16 = 0x02;
+pub const ABS_RX: u16 = 0x03;
+pub const ABS_RY: u16 = 0x04;
+pub const ABS_RZ: u16 = 0x05;
+pub const ABS_THROTTLE: u16 = 0x06;
+pub const ABS_RUDDER: u16 = 0x07;
+pub const ABS_HAT0X: u16 = 0x10;
+pub const ABS_HAT0Y: u16 = 0x11;
+
+pub const BTN_TRIGGER: u16 = 0x120;
+pub const BTN_TRIGGER_HAPPY1: u16 = 0x2c0;
+
+// ioctl request numbers, precomputed from _IO('U', n) and _IOW('U', n, int)
+pub const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+pub const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+pub const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
+pub const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
+pub const UI_SET_ABSBIT: libc::c_ulong = 0x40045567;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct input_id {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+#[repr(C)]
+#[derive(Copy)]
+pub struct uinput_user_dev {
+    pub name: [libc::c_char; UINPUT_MAX_NAME_SIZE],
+    pub id: input_id,
+    pub ff_effects_max: u32,
+    pub absmax: [i32; ABS_CNT],
+    pub absmin: [i32; ABS_CNT],
+    pub absfuzz: [i32; ABS_CNT],
+    pub absflat: [i32; ABS_CNT],
+}
+impl ::std::clone::Clone for uinput_user_dev {
+    fn clone(&self) -> Self { *self }
+}
+impl ::std::default::Default for uinput_user_dev {
+    fn default() -> Self { unsafe { ::std::mem::zeroed() } }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct timeval {
+    pub tv_sec: libc::c_long,
+    pub tv_usec: libc::c_long,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct input_event {
+    pub time: timeval,
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+extern "C" {
+    pub fn ioctl(fd: libc::c_int, request: libc::c_ulong, ...) -> libc::c_int;
+}
@@ -2,6 +2,8 @@ mod vjoyinterface;
 
 extern crate libc;
 
+use demc::virtc::VJoystickDriver;
+
 // Rustifying vJoy wrapper functions + convenience functions
 
 // Wrapper functions
@@ -28,8 +30,6 @@ pub fn is_vjoy_enabled() -> bool {
     pub fn DriverMatch(DllVer: *mut libc::c_ushort,
                        DrvVer: *mut libc::c_ushort) -> libc::c_int;
     pub fn RegisterRemovalCB(cb: RemovalCB, data: *mut libc::c_void) -> ();
-    pub fn GetVJDDiscPovNumber(rID: libc::c_uint) -> libc::c_int;
-    pub fn GetVJDContPovNumber(rID: libc::c_uint) -> libc::c_int;
 */
 
 pub fn get_vjoystick_axis_exists(index: u32, axis: u32) -> bool {
@@ -84,6 +84,18 @@ pub fn get_vjoystick_button_count(index: u32) -> u8 {
     }
 }
 
+pub fn get_vjoystick_disc_pov_count(index: u32) -> u8 {
+    unsafe {
+        vjoyinterface::GetVJDDiscPovNumber(index) as u8
+    }
+}
+
+pub fn get_vjoystick_cont_pov_count(index: u32) -> u8 {
+    unsafe {
+        vjoyinterface::GetVJDContPovNumber(index) as u8
+    }
+}
+
 //@todo     pub fn UpdateVJD(rID: libc::c_uint, pData: *mut libc::c_void) -> libc::c_int;
 
 pub enum VjoystickStatus {
@@ -144,10 +156,25 @@ pub fn set_vjoystick_button(index: u32, button: u8, value: i32) -> Result<(), ()
     }
 }
 
-/*@todo
-    pub fn SetDiscPov(Value: libc::c_int, rID: libc::c_uint, nPov: libc::c_uchar) -> libc::c_int;
-    pub fn SetContPov(Value: libc::c_ulong, rID: libc::c_uint, nPov: libc::c_uchar) -> libc::c_int;
-*/
+// Discrete POV values are -1 (centered), 0 (north), 1 (east), 2 (south) or 3 (west)
+pub fn set_vjoystick_disc_pov(index: u32, pov: u8, value: i32) -> Result<(), ()> {
+    unsafe {
+        match vjoyinterface::SetDiscPov(value, index, pov) {
+            0 => Err(()),
+            _ => Ok(())
+        }
+    }
+}
+
+// Continuous POV values are -1 (centered) or hundredths of a degree clockwise from north, in the range [0, 35999]
+pub fn set_vjoystick_cont_pov(index: u32, pov: u8, value: i32) -> Result<(), ()> {
+    unsafe {
+        match vjoyinterface::SetContPov(value as libc::c_ulong, index, pov) {
+            0 => Err(()),
+            _ => Ok(())
+        }
+    }
+}
 
 
 // Convenience functions
@@ -167,4 +194,75 @@ pub fn claim_vjoystick(index: u32) -> Result<(), &'static str> {
         },
         _ => Err("Virtual joystick is owned by someone else, missing, or in unknown state")
     }
-}
\ No newline at end of file
+}
+
+// Driver
+
+// A VJoystickDriver backed by the vJoy device with the given number
+pub struct VJoyDriver {
+    device_number: u32
+}
+
+impl VJoyDriver {
+    pub fn new(device_number: u32) -> Self {
+        VJoyDriver { device_number: device_number }
+    }
+}
+
+impl VJoystickDriver for VJoyDriver {
+    fn is_enabled(&self) -> bool {
+        is_vjoy_enabled()
+    }
+
+    fn claim(&self) -> Result<(), &'static str> {
+        claim_vjoystick(self.device_number)
+    }
+
+    fn reset(&self) -> Result<(), ()> {
+        reset_vjoystick(self.device_number)
+    }
+
+    fn release(&self) {
+        relinquish_vjoystick(self.device_number)
+    }
+
+    fn get_axis_exists(&self, axis: u32) -> bool {
+        get_vjoystick_axis_exists(self.device_number, axis)
+    }
+
+    fn get_axis_min(&self, axis: u32) -> Result<i64, ()> {
+        get_vjoystick_axis_min(self.device_number, axis)
+    }
+
+    fn get_axis_max(&self, axis: u32) -> Result<i64, ()> {
+        get_vjoystick_axis_max(self.device_number, axis)
+    }
+
+    fn get_button_count(&self) -> u8 {
+        get_vjoystick_button_count(self.device_number)
+    }
+
+    fn set_axis(&self, axis: u32, value: i64) -> Result<(), ()> {
+        set_vjoystick_axis(self.device_number, axis, value)
+    }
+
+    fn set_button(&self, button: u8, value: bool) -> Result<(), ()> {
+        set_vjoystick_button(self.device_number, button, value as i32)
+    }
+
+    fn get_disc_pov_count(&self) -> u8 {
+        get_vjoystick_disc_pov_count(self.device_number)
+    }
+
+    fn get_cont_pov_count(&self) -> u8 {
+        get_vjoystick_cont_pov_count(self.device_number)
+    }
+
+    fn set_disc_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        set_vjoystick_disc_pov(self.device_number, pov, value)
+    }
+
+    fn set_cont_pov(&self, pov: u8, value: i32) -> Result<(), ()> {
+        set_vjoystick_cont_pov(self.device_number, pov, value)
+    }
+}
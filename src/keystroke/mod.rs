@@ -31,6 +31,152 @@ mod platform {
 
 }
 
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::File;
+    use std::sync::{Mutex, Once, ONCE_INIT};
+
+    use demc::virtc::uinput_rust;
+    use demc::virtc::uinput_rust::uinputinterface::EV_KEY;
+
+    use super::{Physical, Key, Scan};
+
+    // Every key code our virtual keyboard can emit; uinput requires these to be declared up front
+    const KEY_CODES: [u16; 42] = [28, 29, 42, 56,
+                                  59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 87, 88,
+                                  30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50,
+                                  49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44];
+
+    fn get_keycode(p: Physical) -> u16 {
+        use super::Physical::*;
+        match p {
+            Return => 28,
+            Control => 29,
+            Shift => 42,
+            Alt => 56,
+            F1 => 59,
+            F2 => 60,
+            F3 => 61,
+            F4 => 62,
+            F5 => 63,
+            F6 => 64,
+            F7 => 65,
+            F8 => 66,
+            F9 => 67,
+            F10 => 68,
+            F11 => 87,
+            F12 => 88,
+            A => 30,
+            B => 48,
+            C => 46,
+            D => 32,
+            E => 18,
+            F => 33,
+            G => 34,
+            H => 35,
+            I => 23,
+            J => 36,
+            K => 37,
+            L => 38,
+            M => 50,
+            N => 49,
+            O => 24,
+            P => 25,
+            Q => 16,
+            R => 19,
+            S => 31,
+            T => 20,
+            U => 22,
+            V => 47,
+            W => 17,
+            X => 45,
+            Y => 21,
+            Z => 44,
+        }
+    }
+
+    pub fn get_scancode(s: Scan) -> u16 {
+        use super::Scan::*;
+        match s {
+            F1 => 59,
+            F2 => 60,
+            F3 => 61,
+            F4 => 62,
+            F5 => 63,
+            F6 => 64,
+            F7 => 65,
+            F8 => 66,
+            F9 => 67,
+            F10 => 68,
+            F11 => 87,
+            F12 => 88
+        }
+    }
+
+    // Our virtual keyboard, created through uinput the first time a key is sent
+    fn get_keyboard() -> &'static Mutex<Option<File>> {
+        static INIT: Once = ONCE_INIT;
+        static mut KEYBOARD: *const Mutex<Option<File>> = 0 as *const Mutex<Option<File>>;
+
+        unsafe {
+            INIT.call_once(|| {
+                let keyboard = uinput_rust::create_uinput_device("TPPM virtual keyboard", 0, &KEY_CODES, &[]).ok();
+                KEYBOARD = Box::into_raw(Box::new(Mutex::new(keyboard)));
+            });
+            &*KEYBOARD
+        }
+    }
+
+    fn send_key_event(k: Key, value: i32) {
+        let code = match k {
+            Key::Physical(p) => get_keycode(p),
+            Key::Scan(sc) => get_scancode(sc),
+            // evdev has no notion of unicode input
+            Key::Unicode(_) => return
+        };
+
+        if let Some(ref mut keyboard) = *get_keyboard().lock().unwrap() {
+            uinput_rust::write_event_and_sync(keyboard, EV_KEY, code, value);
+        }
+    }
+
+    pub fn press_key(k: Key) {
+        send_key_event(k, 1);
+    }
+
+    pub fn release_key(k: Key) {
+        send_key_event(k, 0);
+    }
+
+    pub fn send_combo(keys: &[Key]) {
+        for &k in keys.iter() {
+            press_key(k);
+        }
+        for &k in keys.iter().rev() {
+            release_key(k);
+        }
+    }
+
+    pub fn send_key(k: Key) {
+        press_key(k);
+        release_key(k);
+    }
+
+    /// Send all unicode characters below 0x10000, silently skipping others.
+    pub fn send_char(c: char) {
+        if (c as u64) < 0x10000 {
+            send_key(Key::Unicode(c));
+        }
+    }
+
+    /// Send a string as keyboard events
+    pub fn send_str(msg: &str) {
+        for c in msg.chars() {
+            send_char(c);
+        }
+    }
+}
+
 #[cfg(target_os = "windows")]
 mod platform {
     extern crate winapi;
@@ -4,10 +4,11 @@
 mod tmi;
 mod demc;
 mod keystroke;
+mod roles;
 
-extern crate regex;
 extern crate toml;
 extern crate time;
+extern crate native_tls;
 
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -16,8 +17,13 @@ use std::io::Write;
 use std::path::Path;
 use std::thread;
 
-use demc::{DemC, ChatInterfaced};
-use demc::vgcnc::{VGcnC, sample_gcn_controller_hardware};
+use demc::{DemC, ChatInterfaced, CommandError};
+use demc::virtc;
+use demc::layout::ControllerLayout;
+use demc::vgenc::VGenC;
+use demc::democracy::InputMode;
+use demc::parser::ParseError;
+use roles::{Roles, Role};
 
 
 const CONFIG_FILE_PATH: &'static str = "tppm.toml";
@@ -30,36 +36,69 @@ enum ModCommand {
     SaveState,
     LoadState,
     UnplugController,
-    PlugController
+    PlugController,
+    // Lock chat into a mode, or with None, hand control back to the anarchy/democracy meter
+    LockMode(Option<InputMode>),
+    ShowMeter
 }
-fn parse_mod_commands(sender: &String, msg: &String) -> Option<ModCommand> {
-    match sender.to_lowercase().as_ref() {
-        "twitchplayspapermario"|"xxn1"|"kalarmar"|"rashama_izouki"|"mooismyusername" => {
-            match msg.to_lowercase().as_ref() {
-                "!savestate" => Some(ModCommand::SaveState),
-                "!loadstate" => Some(ModCommand::LoadState),
-                "!unplugcontroller" => Some(ModCommand::UnplugController),
-                "!plugcontroller" => Some(ModCommand::PlugController),
-                _ => None
-            }
-        },
-        _ => None
+impl ModCommand {
+    // The command's name, as used for its permission in [roles.commands]
+    fn get_name(&self) -> &'static str {
+        match *self {
+            ModCommand::SaveState => "savestate",
+            ModCommand::LoadState => "loadstate",
+            ModCommand::UnplugController => "unplugcontroller",
+            ModCommand::PlugController => "plugcontroller",
+            ModCommand::LockMode(Some(InputMode::Anarchy)) => "anarchy",
+            ModCommand::LockMode(Some(InputMode::Democracy)) => "democracy",
+            ModCommand::LockMode(None) => "unlockmode",
+            ModCommand::ShowMeter => "meter"
+        }
+    }
+}
+// Parse a mod command, if the sender's role allows it
+fn parse_mod_commands(role: Role, msg: &String, roles: &Roles) -> Option<ModCommand> {
+    let mod_command = match msg.to_lowercase().as_ref() {
+        "!savestate" => ModCommand::SaveState,
+        "!loadstate" => ModCommand::LoadState,
+        "!unplugcontroller" => ModCommand::UnplugController,
+        "!plugcontroller" => ModCommand::PlugController,
+        "!anarchy" => ModCommand::LockMode(Some(InputMode::Anarchy)),
+        "!democracy" => ModCommand::LockMode(Some(InputMode::Democracy)),
+        "!unlockmode" => ModCommand::LockMode(None),
+        "!meter" => ModCommand::ShowMeter,
+        _ => return None
+    };
+
+    match roles.may_use(role, mod_command.get_name()) {
+        true => Some(mod_command),
+        false => None
     }
 }
 
 
 enum ChatMessageHandler {
     ModCommandHandler,
+    ModeVoteHandler,
     ControllerCommandHandler,
+    // Controller commands that were dropped for overrunning a rate limit
+    RateLimitedHandler,
+    // Controller commands that were malformed or broke a limit
+    RejectedCommandHandler,
 }
-fn log_tmi_message(sender: &String, message: &String, handler: &Option<ChatMessageHandler>, log: &mut File) {
-    let log_string = match handler {
+fn log_tmi_message(channel: &String, sender: &String, message: &String, handler: &Option<ChatMessageHandler>,
+                   log: &mut File) {
+    let message_string = match handler {
         &Some(ref handler) => match handler {
             &ChatMessageHandler::ModCommandHandler => format!("!{}: {}", sender, message),
+            &ChatMessageHandler::ModeVoteHandler => format!("%{}: {}", sender, message),
             &ChatMessageHandler::ControllerCommandHandler => format!("_{}: {}", sender, message),
+            &ChatMessageHandler::RateLimitedHandler => format!("#{}: {}", sender, message),
+            &ChatMessageHandler::RejectedCommandHandler => format!("?{}: {}", sender, message),
         },
         &None => format!("{}: {}", sender, message)
     };
+    let log_string = format!("[{}] {}", channel, message_string);
 
     log.write_all(&log_string.as_bytes());
     log.write_all("\r\n".as_bytes());
@@ -67,31 +106,66 @@ fn log_tmi_message(sender: &String, message: &String, handler: &Option<ChatMessa
     println!("{}", log_string);
 }
 
-fn handle_tmi_message<T>(sender: &String, message: &String, accepting_controller_commands: bool,
-                         controller: &DemC<T>, log: &mut File) -> Option<bool>
+// Reply to a chat message, saying so if the reply can't be sent
+fn reply_to(tmi_stream: &tmi::TmiStream, chat_message: &tmi::ChatMessage, reply: &str) {
+    match tmi_stream.reply(chat_message, reply) {
+        Ok(()) => (),
+        Err(1) => println!("Reply dropped, too many messages are queued: {}", reply),
+        Err(2) => println!("Reply dropped, chat connection has stopped sending: {}", reply),
+        Err(3) => println!("Reply dropped, {} is read-only: {}", chat_message.channel, reply),
+        Err(_) => println!("Reply dropped: {}", reply)
+    }
+}
+
+fn handle_tmi_message<T>(chat_message: &tmi::ChatMessage, accepting_controller_commands: bool,
+                         controller: &DemC<T>, roles: &Roles, tmi_stream: &tmi::TmiStream, log: &mut File)
+                         -> Option<bool>
 {
+    let sender = &chat_message.sender;
+    let message = &chat_message.message;
     let mut message_handler = None;
     let mut new_accept_controller_command_value = None;
+    let role = roles.get_role(sender, &chat_message.badges, &chat_message.channel);
+    // What chat in the message's channel may do; chat in read-only channels is only logged
+    let policy = tmi_stream.get_channel_policy(&chat_message.channel);
     
-    if !message_handler.is_some() {
-        match parse_mod_commands(sender, message) {
+    if !message_handler.is_some() && policy.mod_commands {
+        match parse_mod_commands(role, message, roles) {
             Some(mod_command) => {
                 match mod_command {
                     ModCommand::SaveState => {
                         keystroke::press_key(keystroke::Key::Scan(keystroke::Scan::F1));
                         thread::sleep_ms(500);
                         keystroke::release_key(keystroke::Key::Scan(keystroke::Scan::F1));
+                        reply_to(tmi_stream, chat_message, "State saved.");
                     },
                     ModCommand::LoadState => {
                         keystroke::press_key(keystroke::Key::Scan(keystroke::Scan::F7));
                         thread::sleep_ms(500);
                         keystroke::release_key(keystroke::Key::Scan(keystroke::Scan::F7));
+                        reply_to(tmi_stream, chat_message, "State loaded.");
                     },
                     ModCommand::UnplugController => {
                         new_accept_controller_command_value = Some(false);
+                        reply_to(tmi_stream, chat_message, "Controller unplugged; chat's inputs are ignored.");
                     },
                     ModCommand::PlugController => {
                         new_accept_controller_command_value = Some(true);
+                        reply_to(tmi_stream, chat_message, "Controller plugged in; chat's inputs are back on.");
+                    },
+                    ModCommand::LockMode(mode) => {
+                        controller.lock_mode(mode);
+                        let acknowledgement = match mode {
+                            Some(InputMode::Anarchy) => "Locked into anarchy.",
+                            Some(InputMode::Democracy) => "Locked into democracy.",
+                            None => "Unlocked; the meter decides the mode again."
+                        };
+                        reply_to(tmi_stream, chat_message, acknowledgement);
+                    },
+                    ModCommand::ShowMeter => {
+                        let meter = format!("Meter: {:.1}% democracy, mode {:?}, locked {:?}", controller.get_meter(),
+                                            controller.get_mode(), controller.get_locked_mode());
+                        reply_to(tmi_stream, chat_message, &meter);
                     }
                 }
                 message_handler = Some(ChatMessageHandler::ModCommandHandler);
@@ -100,19 +174,41 @@ fn handle_tmi_message<T>(sender: &String, message: &String, accepting_controller
         }
     }
 
+    if !message_handler.is_some() && policy.input && accepting_controller_commands && roles.may_use(role, "mode_vote") {
+        if controller.handle_mode_vote(message) {
+            message_handler = Some(ChatMessageHandler::ModeVoteHandler);
+        }
+    }
+
     if !message_handler.is_some() {
-        match accepting_controller_commands {
-            true => match controller.handle_commands(message) {
+        match policy.input && accepting_controller_commands && roles.may_use(role, "input") {
+            true => match controller.handle_commands_from(sender, message) {
                 Ok(_) => {
                     message_handler = Some(ChatMessageHandler::ControllerCommandHandler);
                 },
-                Err(_) => ()
+                Err(CommandError::RateLimited(rejection)) => {
+                    message_handler = Some(ChatMessageHandler::RateLimitedHandler);
+                    // Only a user's first dropped line in each window is answered, so that one user can't spend our
+                    // own chat budget and crowd out replies to mods
+                    if controller.note_rejection(sender) {
+                        reply_to(tmi_stream, chat_message, &format!("Input dropped: {}.", rejection));
+                    }
+                },
+                // Lines that aren't commands at all are just chat, and go unanswered
+                Err(CommandError::Parse(ParseError::UnknownWord(_))) |
+                Err(CommandError::Parse(ParseError::Empty)) => (),
+                Err(CommandError::Parse(err)) => {
+                    message_handler = Some(ChatMessageHandler::RejectedCommandHandler);
+                    if controller.note_rejection(sender) {
+                        reply_to(tmi_stream, chat_message, &format!("Input rejected: {}.", err));
+                    }
+                }
             },
             false => ()
         };
     }
     
-    log_tmi_message(sender, message, &message_handler, log);
+    log_tmi_message(&chat_message.channel, sender, message, &message_handler, log);
     
     new_accept_controller_command_value
 }
@@ -121,29 +217,66 @@ fn handle_tmi_message<T>(sender: &String, message: &String, accepting_controller
 
 
 fn main() {
-    let (axes, joysticks, buttons) = sample_gcn_controller_hardware(VJOY_DEVICE_NUMBER).unwrap();
-    let raw_controller = match VGcnC::new(VJOY_DEVICE_NUMBER, axes, joysticks, buttons) {
+    let layout = match ControllerLayout::from_config_file(CONFIG_FILE_PATH) {
+        Ok(layout) => layout,
+        Err(err) => panic!("Unable to load controller layout: err {}", err)
+    };
+
+    let driver = virtc::make_native_driver(VJOY_DEVICE_NUMBER);
+    let raw_controller = match VGenC::new(driver, &layout) {
         Ok(controller) => controller,
         Err(err) => panic!("Unable to make raw controller: err {}", err)
     };
 
+    let constraints = match demc::ControllerConstraints::from_config_file(CONFIG_FILE_PATH, &layout) {
+        Ok(constraints) => constraints,
+        Err(err) => panic!("Unable to load controller constraints: err {}", err)
+    };
+
     // Initialize a democratized virtual controller
-    let controller = match DemC::new(raw_controller, demc::ControllerConstraints {
-        illegal_combinations: vec![
-                                (String::from("start"), vec!(String::from("b"), String::from("x"))),
-                                (String::from("b"), vec!(String::from("start"), String::from("x"))),
-                                (String::from("x"), vec!(String::from("b"), String::from("start")))] } )
-    {
+    let controller = match DemC::with_frame_rate(raw_controller, constraints, layout.frames_per_second) {
         Ok(controller) => controller,
         Err(err) => panic!("Unable to create democratized controller: DemC error {}", err)
     };
 
+    let democracy_config = match demc::democracy::DemocracyConfig::from_config_file(CONFIG_FILE_PATH) {
+        Ok(config) => config,
+        Err(err) => panic!("Unable to load democracy settings: err {}", err)
+    };
+    controller.configure_democracy(&democracy_config);
+
+    let rate_limit_config = match demc::ratelimit::RateLimitConfig::from_config_file(CONFIG_FILE_PATH) {
+        Ok(config) => config,
+        Err(err) => panic!("Unable to load rate limits: err {}", err)
+    };
+    controller.set_rate_limits(&rate_limit_config);
+
+    let roles = match Roles::from_config_file(CONFIG_FILE_PATH) {
+        Ok(roles) => roles,
+        Err(err) => panic!("Unable to load roles: err {}", err)
+    };
+
     // Start our IRC connection
     let tmi_stream = match tmi::TmiStream::establish(CONFIG_FILE_PATH) {
         Ok(stream) => stream,
         Err(err) => panic!("Unable to establish TMI stream: TMI error {}", err)
     };
 
+    // Report on the connection, and give up if Twitch won't have our login
+    let connection_statuses = tmi_stream.subscribe_status();
+    thread::spawn(move|| {
+        for status in connection_statuses.iter() {
+            match status {
+                tmi::ConnectionStatus::Connected => println!("Connected to chat"),
+                tmi::ConnectionStatus::Disconnected => println!("Disconnected from chat; reconnecting"),
+                tmi::ConnectionStatus::AuthenticationFailed => {
+                    println!("Twitch turned down our login; check irc.nick and irc.pass in {}", CONFIG_FILE_PATH);
+                    std::process::exit(1);
+                }
+            }
+        }
+    });
+
     let chat_log_path = Path::new(CHAT_LOG_PATH);
     let mut chat_log_file = match OpenOptions::new().read(true).write(true).append(true).create(true).
                                   open(&chat_log_path)
@@ -157,8 +290,9 @@ fn main() {
     // Poll the IRC connection and handle its messages forever
     loop {
         match tmi_stream.receive() {
-            Ok((sender, message)) => {
-                match handle_tmi_message(&sender, &message, accepting_controller_commands, &controller, &mut chat_log_file) {
+            Ok(chat_message) => {
+                match handle_tmi_message(&chat_message, accepting_controller_commands, &controller, &roles,
+                                         &tmi_stream, &mut chat_log_file) {
                     Some(val) => { accepting_controller_commands = val; },
                     None => ()
                 }
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+
+use toml;
+
+use demc::layout::read_toml_file;
+use tmi::parse_channels;
+
+
+// Who someone in chat is, from least to most trusted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Banned,
+    Viewer,
+    Trusted,
+    Mod,
+    Owner
+}
+
+impl Role {
+    // The role with given name, as written in config files
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "banned" => Some(Role::Banned),
+            "viewer" => Some(Role::Viewer),
+            "trusted" => Some(Role::Trusted),
+            "mod" => Some(Role::Mod),
+            "owner" => Some(Role::Owner),
+            _ => None
+        }
+    }
+}
+
+// The least role each command needs, by command name, unless configured otherwise
+// "input" is sending controller commands, and "mode_vote" voting on the anarchy/democracy meter; the rest are mod
+// commands, named without their "!"
+const DEFAULT_COMMAND_ROLES: [(&'static str, Role); 10] = [("input", Role::Viewer),
+                                                           ("mode_vote", Role::Viewer),
+                                                           ("savestate", Role::Mod),
+                                                           ("loadstate", Role::Mod),
+                                                           ("unplugcontroller", Role::Mod),
+                                                           ("plugcontroller", Role::Mod),
+                                                           ("anarchy", Role::Mod),
+                                                           ("democracy", Role::Mod),
+                                                           ("unlockmode", Role::Mod),
+                                                           ("meter", Role::Mod)];
+
+// Who holds which role, and which role each command needs, read from the [roles] section of a TOML file; see
+// tppm.toml.example
+// Owning our own channel makes its broadcaster an owner. Twitch's broadcaster and moderator badges make their holders
+// owners and mods in our own channel, but only trusted in partner channels, whose broadcasters and mods aren't ours
+pub struct Roles {
+    // Our own channel, eg. "#channel"
+    home_channel: Option<String>,
+    owners: HashSet<String>,
+    mods: HashSet<String>,
+    trusted: HashSet<String>,
+    banned: HashSet<String>,
+    command_roles: HashMap<String, Role>
+}
+
+impl Roles {
+    // Nobody holds a role besides the default, and commands need their default roles
+    pub fn new() -> Self {
+        Roles { home_channel: None,
+                owners: HashSet::new(),
+                mods: HashSet::new(),
+                trusted: HashSet::new(),
+                banned: HashSet::new(),
+                command_roles: DEFAULT_COMMAND_ROLES.iter().map(|&(command, role)| {
+                    (String::from(command), role)
+                }).collect() }
+    }
+
+    // Err(1): Unable to open config file
+    // Err(2): Unable to parse config file as TOML
+    // Err(3): A list of users is malformed
+    // Err(4): A command's role is malformed or unknown
+    pub fn from_config_file(path: &str) -> Result<Self, u8> {
+        let tree = try!(read_toml_file(path));
+        Roles::from_toml(&tree)
+    }
+
+    // Err(3): A list of users is malformed
+    // Err(4): A command's role is malformed or unknown
+    pub fn from_toml(tree: &toml::Value) -> Result<Self, u8> {
+        let mut roles = Roles::new();
+
+        // Our own channel belongs to its broadcaster. Malformed channels are left for the chat connection to report
+        if let Ok(channels) = parse_channels(tree) {
+            let home_channel = channels[0].0.clone();
+            roles.owners.insert(String::from(home_channel.trim_left_matches('#')));
+            roles.home_channel = Some(home_channel);
+        }
+
+        for &key in ["owners", "mods", "trusted", "banned"].iter() {
+            let users_path = format!("roles.{}", key);
+            let users_value = match tree.lookup(&users_path) {
+                Some(users_value) => users_value,
+                None => continue
+            };
+            let users_slice = match users_value.as_slice() {
+                Some(slice) => slice,
+                None => return Err(3)
+            };
+
+            let users = match key {
+                "owners" => &mut roles.owners,
+                "mods" => &mut roles.mods,
+                "trusted" => &mut roles.trusted,
+                _ => &mut roles.banned
+            };
+            for user_value in users_slice.iter() {
+                match user_value.as_str() {
+                    Some(user) => { users.insert(user.to_lowercase()); },
+                    None => return Err(3)
+                }
+            }
+        }
+
+        if let Some(commands_value) = tree.lookup("roles.commands") {
+            let commands_table = match commands_value.as_table() {
+                Some(table) => table,
+                None => return Err(4)
+            };
+            for (command, role_value) in commands_table.iter() {
+                match role_value.as_str().and_then(Role::from_name) {
+                    Some(role) => { roles.command_roles.insert(command.clone(), role); },
+                    None => return Err(4)
+                }
+            }
+        }
+
+        Ok(roles)
+    }
+
+    // The role of the given user, who wears the given Twitch badges, eg. "moderator/1", in the given channel
+    // Owners can't be banned, but anyone else can, Twitch mods included
+    pub fn get_role(&self, user: &str, badges: &[String], channel: &str) -> Role {
+        let user = user.to_lowercase();
+        let has_badge = |name: &str| badges.iter().any(|badge| badge.split('/').next() == Some(name));
+        let in_home_channel = self.home_channel.as_ref().map_or(false, |home_channel| home_channel == channel);
+
+        if self.owners.contains(&user) || (in_home_channel && has_badge("broadcaster")) {
+            Role::Owner
+        } else if self.banned.contains(&user) {
+            Role::Banned
+        } else if self.mods.contains(&user) || (in_home_channel && has_badge("moderator")) {
+            Role::Mod
+        } else if self.trusted.contains(&user) || has_badge("broadcaster") || has_badge("moderator") {
+            Role::Trusted
+        } else {
+            Role::Viewer
+        }
+    }
+
+    // Whether the given role may use the command with given name
+    // Commands without a configured role are for owners only
+    pub fn may_use(&self, role: Role, command: &str) -> bool {
+        role >= *self.command_roles.get(command).unwrap_or(&Role::Owner)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use roles::{Roles, Role};
+
+    #[test]
+    fn test_roles_and_permissions() {
+        let tree: toml::Value = r##"
+            [irc]
+            channel = "#TheStreamer"
+
+            [roles]
+            mods = ["ModUser"]
+            trusted = ["regular"]
+            banned = ["spammer", "thestreamer"]
+
+            [roles.commands]
+            savestate = "trusted"
+        "##.parse().unwrap();
+        let roles = Roles::from_toml(&tree).unwrap();
+        let home = "#thestreamer";
+
+        assert_eq!(roles.get_role("thestreamer", &[], home), Role::Owner);
+        assert_eq!(roles.get_role("moduser", &[], home), Role::Mod);
+        assert_eq!(roles.get_role("someone", &[String::from("moderator/1")], home), Role::Mod);
+        assert_eq!(roles.get_role("spammer", &[String::from("moderator/1")], home), Role::Banned);
+        assert_eq!(roles.get_role("regular", &[], home), Role::Trusted);
+        assert_eq!(roles.get_role("someone", &[String::from("subscriber/12")], home), Role::Viewer);
+
+        // A partner channel's broadcaster and mods are only trusted, though configured roles hold everywhere
+        assert_eq!(roles.get_role("partner", &[String::from("broadcaster/1")], "#partner"), Role::Trusted);
+        assert_eq!(roles.get_role("someone", &[String::from("moderator/1")], "#partner"), Role::Trusted);
+        assert_eq!(roles.get_role("moduser", &[], "#partner"), Role::Mod);
+        assert_eq!(roles.get_role("thestreamer", &[], "#partner"), Role::Owner);
+
+        assert!(roles.may_use(Role::Trusted, "savestate"));
+        assert!(!roles.may_use(Role::Trusted, "loadstate"));
+        assert!(roles.may_use(Role::Viewer, "input"));
+        assert!(!roles.may_use(Role::Banned, "input"));
+        assert!(!roles.may_use(Role::Mod, "unknown"));
+
+        // Without irc.channel, our own channel is the first of irc.channels
+        let tree: toml::Value = r##"
+            [[irc.channels]]
+            name = "TheStreamer"
+
+            [[irc.channels]]
+            name = "#partner"
+        "##.parse().unwrap();
+        let roles = Roles::from_toml(&tree).unwrap();
+        assert_eq!(roles.get_role("thestreamer", &[], home), Role::Owner);
+        assert_eq!(roles.get_role("someone", &[String::from("moderator/1")], home), Role::Mod);
+        assert_eq!(roles.get_role("someone", &[String::from("moderator/1")], "#partner"), Role::Trusted);
+    }
+}
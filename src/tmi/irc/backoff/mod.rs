@@ -0,0 +1,71 @@
+use std;
+
+use time::{Duration, get_time};
+
+
+// How long, in milliseconds, to wait before the first reconnection attempt, and the most to wait before any
+pub const DEFAULT_INITIAL_DELAY: u32 = 1000;
+pub const DEFAULT_MAX_DELAY: u32 = 120000;
+
+
+// Spaces out reconnection attempts, so that we don't hammer a server that's down, or that's turning us away
+// Each delay doubles the one before, up to a maximum, and is jittered to somewhere between half and all of that, so
+// that clients that lost their connections together don't all come back together
+pub struct Backoff {
+    initial_delay: u32,
+    max_delay: u32,
+    attempts: u32,
+    // xorshift state for the jitter
+    seed: u64
+}
+
+impl Backoff {
+    pub fn new(initial_delay: u32, max_delay: u32) -> Self {
+        let time_now = get_time();
+        Backoff { initial_delay: initial_delay,
+                  max_delay: max_delay,
+                  attempts: 0,
+                  seed: ((time_now.sec as u64).wrapping_mul(1000000007) ^ time_now.nsec as u64) | 1 }
+    }
+
+    // How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let doublings = std::cmp::min(self.attempts, 31);
+        let delay = std::cmp::min(self.initial_delay as u64 * (1u64 << doublings), self.max_delay as u64);
+        self.attempts += 1;
+
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let half = delay / 2;
+        Duration::milliseconds((delay - half + self.seed % (half + 1)) as i64)
+    }
+
+    // An attempt succeeded; the next failure starts over from the initial delay
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tmi::irc::backoff::Backoff;
+
+    #[test]
+    fn test_delays_double_up_to_the_max_with_jitter() {
+        let mut backoff = Backoff::new(1000, 5000);
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            delays.push(backoff.next_delay().num_milliseconds());
+        }
+
+        for (&delay, &(least, most)) in delays.iter().zip([(500, 1000), (1000, 2000), (2000, 4000), (2500, 5000),
+                                                          (2500, 5000), (2500, 5000)].iter()) {
+            assert!(least <= delay && delay <= most, "{} not in [{}, {}]", delay, least, most);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay().num_milliseconds() <= 1000);
+    }
+}
@@ -0,0 +1,234 @@
+use std;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use native_tls::{TlsConnector, TlsStream, Certificate};
+
+
+// How long, in milliseconds, a read waits on the connection before giving writers a turn at it
+const READ_POLL_INTERVAL: u64 = 100;
+
+
+// How a connection to an IRC server is secured
+#[derive(Clone, Debug, PartialEq)]
+pub enum Security {
+    // In the clear, as on port 6667
+    Plain,
+    // Over TLS, as on port 6697, verifying the server's certificate against the system's trusted CAs, and the CA in the
+    // given PEM file, if any
+    Tls(Option<String>)
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>)
+}
+
+
+// Opens connections to IRC servers, secured as configured
+#[derive(Clone)]
+pub struct Connector {
+    tls_connector: Option<TlsConnector>
+}
+
+impl Connector {
+    // Err(1): Unable to open or read the CA file
+    // Err(2): Unable to parse the CA file as a PEM certificate
+    // Err(3): Unable to set up TLS
+    pub fn new(security: &Security) -> Result<Self, u8> {
+        let ca_file = match *security {
+            Security::Plain => return Ok(Connector { tls_connector: None }),
+            Security::Tls(ref ca_file) => ca_file
+        };
+
+        let mut builder = TlsConnector::builder();
+        if let Some(ref ca_file) = *ca_file {
+            let mut ca_pem = Vec::new();
+            match File::open(ca_file).and_then(|mut file| file.read_to_end(&mut ca_pem)) {
+                Ok(_) => (),
+                Err(_) => return Err(1)
+            }
+            match Certificate::from_pem(&ca_pem) {
+                Ok(ca) => { builder.add_root_certificate(ca); },
+                Err(_) => return Err(2)
+            }
+        }
+
+        match builder.build() {
+            Ok(tls_connector) => Ok(Connector { tls_connector: Some(tls_connector) }),
+            Err(_) => Err(3)
+        }
+    }
+
+    // Connect to the given server, as "host:port"; over TLS, the server's certificate has to be valid for host
+    // Err(1): Unable to connect to the server
+    // Err(2): TLS handshake failed, eg. the server's certificate didn't verify
+    pub fn connect(&self, server: &str) -> Result<Connection, u8> {
+        let tcp_stream = match TcpStream::connect(server) {
+            Ok(tcp_stream) => tcp_stream,
+            Err(_) => return Err(1)
+        };
+
+        let stream = match self.tls_connector {
+            Some(ref tls_connector) => {
+                let host = server.rsplitn(2, ':').last().unwrap_or(server);
+                match tls_connector.connect(host, tcp_stream) {
+                    Ok(tls_stream) => Stream::Tls(tls_stream),
+                    Err(_) => return Err(2)
+                }
+            },
+            None => Stream::Plain(tcp_stream)
+        };
+
+        // Reads time out now and then, so that they don't hold the connection from writers for long
+        let read_timeout = Some(std::time::Duration::from_millis(READ_POLL_INTERVAL));
+        let timeout_result = match stream {
+            Stream::Plain(ref tcp_stream) => tcp_stream.set_read_timeout(read_timeout),
+            Stream::Tls(ref tls_stream) => tls_stream.get_ref().set_read_timeout(read_timeout)
+        };
+        if timeout_result.is_err() {
+            return Err(1);
+        }
+
+        Ok(Connection { stream: Arc::new(Mutex::new(stream)), writers_waiting: Arc::new(AtomicUsize::new(0)) })
+    }
+}
+
+
+// A connection to an IRC server, in the clear or over TLS
+// Clones share the connection, so that one thread can write to it while another is waiting to read from it
+#[derive(Clone)]
+pub struct Connection {
+    stream: Arc<Mutex<Stream>>,
+    writers_waiting: Arc<AtomicUsize>
+}
+
+impl Connection {
+    // Close the connection, so that reading from it, in any thread, stops waiting
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.with_stream_for_writing(|stream| match *stream {
+            Stream::Plain(ref tcp_stream) => tcp_stream.shutdown(Shutdown::Both),
+            Stream::Tls(ref tls_stream) => tls_stream.get_ref().shutdown(Shutdown::Both)
+        })
+    }
+
+    // Read whatever arrives within the read poll interval, or None if nothing does
+    pub fn read_available(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let result = match *self.stream.lock().unwrap() {
+            Stream::Plain(ref mut tcp_stream) => tcp_stream.read(buf),
+            Stream::Tls(ref mut tls_stream) => tls_stream.read(buf)
+        };
+
+        match result {
+            Ok(bytes_read) => Ok(Some(bytes_read)),
+            // Timed out; let anyone waiting to write go first
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                while self.writers_waiting.load(Ordering::SeqCst) > 0 {
+                    thread::yield_now();
+                }
+                Ok(None)
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    // Do something with the stream, taking it ahead of any reader waiting on it
+    fn with_stream_for_writing<F, T>(&self, f: F) -> T where F: FnOnce(&mut Stream) -> T {
+        self.writers_waiting.fetch_add(1, Ordering::SeqCst);
+        let mut stream = self.stream.lock().unwrap();
+        self.writers_waiting.fetch_sub(1, Ordering::SeqCst);
+        f(&mut *stream)
+    }
+}
+
+impl Read for Connection {
+    // Blocks until there's something to read
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match try!(self.read_available(buf)) {
+                Some(bytes_read) => return Ok(bytes_read),
+                None => ()
+            }
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_stream_for_writing(|stream| match *stream {
+            Stream::Plain(ref mut tcp_stream) => tcp_stream.write(buf),
+            Stream::Tls(ref mut tls_stream) => tls_stream.write(buf)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_stream_for_writing(|stream| match *stream {
+            Stream::Plain(ref mut tcp_stream) => tcp_stream.flush(),
+            Stream::Tls(ref mut tls_stream) => tls_stream.flush()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use native_tls::{Identity, TlsAcceptor};
+
+    use tmi::irc::connection::{Connector, Security};
+
+    // Throwaway certificates; see tests/fixtures/tls/README.md
+    const TEST_CA_FILE: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem");
+
+    #[test]
+    fn test_tls_connections_verify_the_server() {
+        // A TLS IRC server for localhost, whose certificate is signed by a test CA
+        let identity = Identity::from_pkcs8(include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"),
+                                                                   "/tests/fixtures/tls/localhost.pem")),
+                                            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"),
+                                                                   "/tests/fixtures/tls/localhost.key"))).unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("localhost:{}", listener.local_addr().unwrap().port());
+
+        let server_thread = thread::spawn(move|| {
+            for tcp_stream in listener.incoming().take(2) {
+                // Handshakes with clients that don't trust the test CA fail
+                if let Ok(tls_stream) = acceptor.accept(tcp_stream.unwrap()) {
+                    let mut reader = BufReader::new(tls_stream);
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    assert_eq!(line, "NICK tppbot\r\n");
+                    reader.get_mut().write_all(b"PING :tmi.twitch.tv\r\n").unwrap();
+                }
+            }
+        });
+
+        // The test CA isn't trusted by default
+        assert_eq!(Connector::new(&Security::Tls(None)).unwrap().connect(&server).err(), Some(2));
+
+        let connector = Connector::new(&Security::Tls(Some(String::from(TEST_CA_FILE)))).unwrap();
+        let mut connection = connector.connect(&server).unwrap();
+
+        // Writing from another thread gets through while we're waiting to read
+        let mut writer = connection.clone();
+        thread::spawn(move|| {
+            thread::sleep_ms(200);
+            writer.write_all(b"NICK tppbot\r\n").unwrap();
+        });
+
+        let mut received = [0u8; 21];
+        connection.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &b"PING :tmi.twitch.tv\r\n"[..]);
+
+        server_thread.join().unwrap();
+    }
+}
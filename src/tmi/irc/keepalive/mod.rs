@@ -0,0 +1,114 @@
+use time::{Timespec, Duration};
+
+
+// How long, in milliseconds, the server may be quiet before we PING it, and how long it then has to PONG
+pub const DEFAULT_PING_INTERVAL: u32 = 60000;
+pub const DEFAULT_PONG_TIMEOUT: u32 = 20000;
+
+
+// What to do to keep a connection alive
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeepaliveAction {
+    Wait,
+    // PING the server with the given token
+    SendPing(String),
+    // The server didn't answer our PING in time; the connection's dead
+    Dead
+}
+
+// Tells a connection that's merely quiet from one that's silently died
+// Whenever the server's been quiet for the ping interval, we PING it, and if it doesn't PONG back with our PING's token
+// within the pong timeout, the connection is dead
+pub struct Keepalive {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    last_heard: Timespec,
+    // The token of our unanswered PING, and when we sent it, if any
+    ping_sent: Option<(String, Timespec)>,
+    pings_sent: u64
+}
+
+impl Keepalive {
+    pub fn new(ping_interval: u32, pong_timeout: u32, time_now: Timespec) -> Self {
+        Keepalive { ping_interval: Duration::milliseconds(ping_interval as i64),
+                    pong_timeout: Duration::milliseconds(pong_timeout as i64),
+                    last_heard: time_now,
+                    ping_sent: None,
+                    pings_sent: 0 }
+    }
+
+    // Start over, for a new connection
+    pub fn reset(&mut self, time_now: Timespec) {
+        self.last_heard = time_now;
+        self.ping_sent = None;
+    }
+
+    // The server sent us something
+    pub fn heard(&mut self, time_now: Timespec) {
+        self.last_heard = time_now;
+    }
+
+    // The server sent us a PONG with the given token; it answers our PING if the tokens match
+    pub fn pong_received(&mut self, token: &str, time_now: Timespec) {
+        self.heard(time_now);
+        let answered = match self.ping_sent {
+            Some((ref ping_token, _)) => ping_token == token,
+            None => false
+        };
+        if answered {
+            self.ping_sent = None;
+        }
+    }
+
+    // What to do now; the caller has to send any PING asked for
+    pub fn poll(&mut self, time_now: Timespec) -> KeepaliveAction {
+        match self.ping_sent {
+            Some((_, ping_time)) => match time_now - ping_time >= self.pong_timeout {
+                true => KeepaliveAction::Dead,
+                false => KeepaliveAction::Wait
+            },
+            None if time_now - self.last_heard >= self.ping_interval => {
+                self.pings_sent += 1;
+                let token = format!("tppm-{}", self.pings_sent);
+                self.ping_sent = Some((token.clone(), time_now));
+                KeepaliveAction::SendPing(token)
+            },
+            None => KeepaliveAction::Wait
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use time::{Timespec, Duration};
+
+    use tmi::irc::keepalive::{Keepalive, KeepaliveAction};
+
+    #[test]
+    fn test_unanswered_pings_mean_a_dead_connection() {
+        let start = Timespec::new(0, 0);
+        let at = |ms: i64| start + Duration::milliseconds(ms);
+        let mut keepalive = Keepalive::new(1000, 500, start);
+
+        // Hearing from the server puts off our PING
+        keepalive.heard(at(600));
+        assert_eq!(keepalive.poll(at(1000)), KeepaliveAction::Wait);
+        assert_eq!(keepalive.poll(at(1600)), KeepaliveAction::SendPing(String::from("tppm-1")));
+
+        // Chat isn't an answer to our PING, and neither is a PONG with the wrong token
+        keepalive.heard(at(1700));
+        keepalive.pong_received("tppm-0", at(1800));
+        assert_eq!(keepalive.poll(at(2000)), KeepaliveAction::Wait);
+        keepalive.pong_received("tppm-1", at(2050));
+        assert_eq!(keepalive.poll(at(2500)), KeepaliveAction::Wait);
+
+        assert_eq!(keepalive.poll(at(3050)), KeepaliveAction::SendPing(String::from("tppm-2")));
+        assert_eq!(keepalive.poll(at(3549)), KeepaliveAction::Wait);
+        assert_eq!(keepalive.poll(at(3550)), KeepaliveAction::Dead);
+
+        // A new connection gets a fresh start
+        keepalive.reset(at(4000));
+        assert_eq!(keepalive.poll(at(4500)), KeepaliveAction::Wait);
+    }
+}
@@ -0,0 +1,78 @@
+use std::io;
+
+use tmi::irc::connection::Connection;
+
+
+// How much we read from the connection at a time
+const READ_CHUNK_SIZE: usize = 4096;
+// Lines longer than this, in bytes, are dropped; IRCv3 allows 8191 bytes of tags plus 512 of message
+const MAX_LINE_LENGTH: usize = 8191 + 512;
+
+
+// Splits what arrives on a connection into lines, reading as much as is available at a time
+pub struct LineReader {
+    buffer: Vec<u8>,
+    // Where the next line in the buffer starts
+    line_start: usize,
+    // Whether we're dropping the rest of a line that got too long
+    dropping: bool
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        LineReader { buffer: Vec::with_capacity(READ_CHUNK_SIZE), line_start: 0, dropping: false }
+    }
+
+    // The next line, with its line ending, or None if a whole line doesn't arrive within the connection's read poll
+    // interval
+    // Lines end with LF, usually after a CR. Err is an error reading the connection, or UnexpectedEof once the server's
+    // closed it
+    pub fn read_line(&mut self, connection: &mut Connection) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(line) = self.next_buffered_line() {
+                return Ok(Some(line));
+            }
+
+            // Make room for more at the end of the buffer, dropping the lines we've handed out
+            self.buffer.drain(..self.line_start);
+            self.line_start = 0;
+            if self.buffer.len() > MAX_LINE_LENGTH {
+                self.buffer.clear();
+                self.dropping = true;
+            }
+
+            let buffer_length = self.buffer.len();
+            self.buffer.resize(buffer_length + READ_CHUNK_SIZE, 0);
+            let read_result = connection.read_available(&mut self.buffer[buffer_length..]);
+            let bytes_read = match read_result {
+                Ok(Some(bytes_read)) => bytes_read,
+                _ => 0
+            };
+            self.buffer.truncate(buffer_length + bytes_read);
+
+            match read_result {
+                Ok(Some(0)) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(None),
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    fn next_buffered_line(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let line_end = match self.buffer[self.line_start..].iter().position(|&byte| byte == b'\n') {
+                Some(line_length) => self.line_start + line_length + 1,
+                None => return None
+            };
+            let line = self.buffer[self.line_start..line_end].to_vec();
+            self.line_start = line_end;
+
+            if self.dropping {
+                self.dropping = false;
+            } else {
+                return Some(line);
+            }
+        }
+    }
+}
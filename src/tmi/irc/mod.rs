@@ -1,68 +1,135 @@
 #![allow(dead_code)]
 
-use std::net::TcpStream;
-use std::io::Read;
+pub mod backoff;
+pub mod connection;
+pub mod keepalive;
+pub mod lines;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::io::Write;
 use std::str::FromStr;
 use std::thread;
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
+use std;
 use std::ops;
 
-use regex::Regex;
+use time::{Timespec, Duration, get_time};
+
+use demc::clock::to_std_duration;
+
+use self::connection::{Connector, Connection, Security};
+use self::keepalive::{Keepalive, KeepaliveAction, DEFAULT_PING_INTERVAL, DEFAULT_PONG_TIMEOUT};
+use self::backoff::{Backoff, DEFAULT_INITIAL_DELAY, DEFAULT_MAX_DELAY};
+use self::lines::LineReader;
+
+
+// Twitch capabilities we request when connecting: message tags, Twitch-specific commands, and JOIN/PART notices
+const TWITCH_CAPABILITIES: &'static str = "twitch.tv/tags twitch.tv/commands twitch.tv/membership";
+
+// Twitch lets us send this many chat messages per window, or more to channels we're a mod or the broadcaster of
+const MESSAGE_LIMIT_WINDOW: i64 = 30000;
+const MESSAGE_LIMIT: usize = 20;
+const MOD_MESSAGE_LIMIT: usize = 100;
+// Messages past this many waiting to be sent are refused, rather than sent long after they were meant
+const MAX_QUEUED_MESSAGES: usize = 50;
+// How often, in milliseconds, we check whether the connection needs a PING, or has died
+const KEEPALIVE_CHECK_INTERVAL: u64 = 100;
+
+
+// IRCv3 message tags
+// Tags are optional in IRC messages, and map keys to values with metadata about the message, eg. a Twitch user's
+// display name and badges. Tags without a value map to an empty string
+pub type Tags = HashMap<String, String>;
+
+// Parse a message's tags, given without their leading '@'
+fn parse_tags(s: &str) -> Tags {
+    let mut tags = HashMap::new();
+
+    for tag in s.split(';').filter(|tag| !tag.is_empty()) {
+        let mut key_value = tag.splitn(2, '=');
+        let key = key_value.next().unwrap_or("");
+        let value = key_value.next().unwrap_or("");
+        tags.insert(String::from(key), unescape_tag_value(value));
+    }
+
+    tags
+}
+
+// Undo IRCv3 tag value escaping: \: is a semicolon, \s a space, \\ a backslash, and \r and \n CR and LF
+// Any other escaped character stands for itself, and a lone trailing backslash is dropped
+fn unescape_tag_value(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => result.push(';'),
+            Some('s') => result.push(' '),
+            Some('r') => result.push('\r'),
+            Some('n') => result.push('\n'),
+            Some(escaped) => result.push(escaped),
+            None => ()
+        }
+    }
+
+    result
+}
+
+fn escape_tag_value(s: &str) -> String {
+    let mut result = String::new();
+
+    for c in s.chars() {
+        match c {
+            ';' => result.push_str("\\:"),
+            ' ' => result.push_str("\\s"),
+            '\\' => result.push_str("\\\\"),
+            '\r' => result.push_str("\\r"),
+            '\n' => result.push_str("\\n"),
+            _ => result.push(c)
+        }
+    }
+
+    result
+}
 
 
 // IRC Prefix type
 // This field is optional in IRC messages, and contains information about the sender, source username and host
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Prefix {
     pub servername_nick: String,
     pub user: Option<String>,
     pub host: Option<String>
 }
 
-// Create a Prefix from a str
-// Err(1): IRC prefix regex didn't match
-// Err(2): Internal error
+// Create a Prefix from a str, given without its leading ':'
+// A prefix is a servername, or a nick followed by an optional "!user" and an optional "@host"
+// Err(1): the prefix has no servername or nick
 impl FromStr for Prefix {
     type Err = u8;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new(r"(?P<servername_nick>[^!]+)(!(?P<user>[^@]*))?(@(?P<host>.*))?").unwrap();
-        
-        let caps = match re.captures(s) {
-            Some(cap) => cap,
-            None => return Err(1)
+        let (s, host) = match s.find('@') {
+            Some(host_start) => (&s[..host_start], Some(String::from(&s[host_start+1..]))),
+            None => (s, None)
         };
-        
-        let servername_nick_group = caps.name("servername_nick");
-        let user_group = caps.name("user");
-        let host_group = caps.name("host");
-
-        // The servername_nick group should never be empty if the regex matched
-        // Return an internal error (in either this function or the regex crate) if it is
-        let servername_nick_str = match servername_nick_group {
-            Some(servername_nick_str) => servername_nick_str,
-            None => return Err(2)
+        let (servername_nick, user) = match s.find('!') {
+            Some(user_start) => (&s[..user_start], Some(String::from(&s[user_start+1..]))),
+            None => (s, None)
         };
-        // Similarly, the servername_nick group should never contain less than one character
-        // Check it just in case of an internal error
-        if servername_nick_str.is_empty() {
-            return Err(2);
+
+        if servername_nick.is_empty() {
+            return Err(1);
         }
 
-        // All mandatory fields are good... make the struct
-        Ok(Prefix {
-            servername_nick: FromStr::from_str(servername_nick_str).unwrap(),
-            user: match user_group {
-                Some(user) => Some(FromStr::from_str(user).unwrap()),
-                None => None
-            },
-            host: match host_group {
-                Some(host) => Some(FromStr::from_str(host).unwrap()),
-                None => None
-            }
-        })
+        Ok(Prefix { servername_nick: String::from(servername_nick), user: user, host: host })
     }
 }
 
@@ -94,80 +161,134 @@ impl Into<String> for Prefix {
 
 // An enumeration of IRC command types
 // The command is a mandatory field in IRC messages
-// We only implement support for a very small subset - the ones needed to establish and maintain a bare connection
-#[derive(Debug)]
+// The commands we act on, or send, have variants of their own; any other numeric reply or command is kept as is
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     ReplyWelcome,
+    Cap,
     Pass,
     Nick,
     Join,
+    Part,
     ReplyEndOfNames,
     Privmsg,
+    Notice,
     Ping,
-    Pong
+    Pong,
+    // Twitch's own commands, from the twitch.tv/commands capability
+    ClearChat,
+    ClearMsg,
+    GlobalUserState,
+    HostTarget,
+    Reconnect,
+    RoomState,
+    UserNotice,
+    UserState,
+    Whisper,
+    // A three-digit numeric reply without a variant of its own
+    Numeric(u16),
+    // A command without a variant of its own, as it was received
+    Other(String)
 }
 
 // Create a Command from a str
-// Err(1): the command is probably not in our internal enum of IRC commands. Otherwise, it's not an IRC command
+// Commands are case-insensitive words, or three-digit numeric replies
+// Err(1): not an IRC command
 impl FromStr for Command {
     type Err = u8;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "001"|"RPL_WELCOME"     =>  Ok(Command::ReplyWelcome),
-            "PASS"                  =>  Ok(Command::Pass),
-            "NICK"                  =>  Ok(Command::Nick),
-            "JOIN"                  =>  Ok(Command::Join),
-            "366"|"RPL_ENDOFNAMES"  =>  Ok(Command::ReplyEndOfNames),
-            "PRIVMSG"               =>  Ok(Command::Privmsg),
-            "PING"                  =>  Ok(Command::Ping),
-            "PONG"                  =>  Ok(Command::Pong),
-            _                       =>  Err(1)
+        match &s.to_uppercase()[..] {
+            "001"               =>  Ok(Command::ReplyWelcome),
+            "CAP"               =>  Ok(Command::Cap),
+            "PASS"              =>  Ok(Command::Pass),
+            "NICK"              =>  Ok(Command::Nick),
+            "JOIN"              =>  Ok(Command::Join),
+            "PART"              =>  Ok(Command::Part),
+            "366"               =>  Ok(Command::ReplyEndOfNames),
+            "PRIVMSG"           =>  Ok(Command::Privmsg),
+            "NOTICE"            =>  Ok(Command::Notice),
+            "PING"              =>  Ok(Command::Ping),
+            "PONG"              =>  Ok(Command::Pong),
+            "CLEARCHAT"         =>  Ok(Command::ClearChat),
+            "CLEARMSG"          =>  Ok(Command::ClearMsg),
+            "GLOBALUSERSTATE"   =>  Ok(Command::GlobalUserState),
+            "HOSTTARGET"        =>  Ok(Command::HostTarget),
+            "RECONNECT"         =>  Ok(Command::Reconnect),
+            "ROOMSTATE"         =>  Ok(Command::RoomState),
+            "USERNOTICE"        =>  Ok(Command::UserNotice),
+            "USERSTATE"         =>  Ok(Command::UserState),
+            "WHISPER"           =>  Ok(Command::Whisper),
+            _ => {
+                if s.len() == 3 && s.chars().all(|c| c.is_digit(10)) {
+                    Ok(Command::Numeric(s.parse().unwrap()))
+                } else if !s.is_empty() && s.chars().all(|c| match c { 'a'...'z' | 'A'...'Z' => true, _ => false }) {
+                    Ok(Command::Other(String::from(s)))
+                } else {
+                    Err(1)
+                }
+            }
         }
     }
 }
 
-// Convert a Command into a &str
-impl Into<&'static str> for Command {
-    fn into(self) -> &'static str {
-        match self {
-            Command::ReplyWelcome => "RPL_WELCOME",
+// Convert a Command into a String, as it's written on the wire
+impl Into<String> for Command {
+    fn into(self) -> String {
+        let command = match self {
+            Command::ReplyWelcome => "001",
+            Command::Cap => "CAP",
             Command::Pass => "PASS",
             Command::Nick => "NICK",
             Command::Join => "JOIN",
-            Command::ReplyEndOfNames => "RPL_ENDOFNAMES",
+            Command::Part => "PART",
+            Command::ReplyEndOfNames => "366",
             Command::Privmsg => "PRIVMSG",
+            Command::Notice => "NOTICE",
             Command::Ping => "PING",
-            Command::Pong => "PONG"
-        }
+            Command::Pong => "PONG",
+            Command::ClearChat => "CLEARCHAT",
+            Command::ClearMsg => "CLEARMSG",
+            Command::GlobalUserState => "GLOBALUSERSTATE",
+            Command::HostTarget => "HOSTTARGET",
+            Command::Reconnect => "RECONNECT",
+            Command::RoomState => "ROOMSTATE",
+            Command::UserNotice => "USERNOTICE",
+            Command::UserState => "USERSTATE",
+            Command::Whisper => "WHISPER",
+            Command::Numeric(numeric) => return format!("{:03}", numeric),
+            Command::Other(command) => return command
+        };
+
+        String::from(command)
     }
 }
 
 
+// The most parameters an IRC message may have; the last one takes up the rest of the line, even without a ':'
+const MAX_PARAMS: usize = 15;
+
 // IRC Parameters type
 // Parameters are optional in IRC messages, and are a collection of strings that comprise a message's payload
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Params(Vec<String>);
 
 // Convert a set of parameters into a String
+// Only the last parameter may be empty, contain spaces or start with a ':'; it's written as a trailing parameter when
+// it does
 impl Into<String> for Params {
     fn into(self) -> String {
+        let num_params = self.len();
         let mut result = String::new();
 
-        // C-style... is there a better way to identify the last element?
-        let iter = self.iter();
-        let num_params = iter.clone().count();
-        let mut param_on = 0;
-
-        for param in iter {
-            param_on = param_on + 1;
-            if param_on == num_params {
-               result.push_str(":");
-            }
-            result.push_str(&param);
-            if param_on != num_params {
+        for (i, param) in self.iter().enumerate() {
+            if i > 0 {
                 result.push_str(" ");
             }
+            if i + 1 == num_params && (param.is_empty() || param.contains(' ') || param.starts_with(":")) {
+                result.push_str(":");
+            }
+            result.push_str(&param);
         }
 
         result
@@ -196,210 +317,421 @@ impl From<Vec<String>> for Params {
 }
 
 
+// Split the next space-delimited token off the front of s, returning it and what follows the spaces after it
+fn split_token(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(token_end) => (&s[..token_end], s[token_end..].trim_left_matches(' ')),
+        None => (s, "")
+    }
+}
+
 // A representation of an IRC message
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct IrcMessage {
+    pub tags: Tags,
     pub prefix: Option<Prefix>,
     pub command: Command,
     pub params: Option<Params>
 }
 
-// Create an IrcMessage from a str
-// Err(1): str does not match our IRC regex
-// Err(2): command does not match our list of IRC commands
-// Err(3): internal error: command group not found
+// Create an IrcMessage from a str, following RFC 1459 and 2812, with IRCv3 message tags:
+// ["@" tags " "] [":" prefix " "] command [" " middle]* [" :" trailing]
+// The line's CRLF, or lone CR or LF, is optional; any other CR or LF is kept in the parameters
+// Err(1): the line has no command
+// Err(2): the command is neither a word nor a three-digit numeric reply
+// Err(3): the prefix has no servername or nick
 impl FromStr for IrcMessage {
     type Err = u8;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // Dissect the message to identify its prefix (if present), its command (if present), and its
-        // arguments (if present)
-        //@todo probably a bad idea to have written this regex, google "irc regex" and replace this
-        let re = Regex::new(r"(^:(?P<prefix>([:alnum:]|[:punct:])+) )?((?P<command>([:alnum:])+)? ?)(?P<params>.*?)\r").unwrap();
+        let s = if s.ends_with("\r\n") {
+            &s[..s.len()-2]
+        } else if s.ends_with("\n") || s.ends_with("\r") {
+            &s[..s.len()-1]
+        } else {
+            s
+        };
+        let mut rest = s.trim_left_matches(' ');
 
-        let cap_option = re.captures(s);
-        if cap_option.is_none() {
-            return Err(1);
+        let mut tags = Tags::new();
+        if rest.starts_with("@") {
+            let (tags_str, after_tags) = split_token(&rest[1..]);
+            tags = parse_tags(tags_str);
+            rest = after_tags;
         }
-        let caps = cap_option.unwrap();
-
-        let prefix_group = caps.name("prefix");
-        let command_group = caps.name("command");
-        let params_group = caps.name("params");
-
-        // Populate an IrcMessage struct with the fields, splitting up the parameters (if present)
-        // into a list of Strings
-        Ok(IrcMessage {
-            prefix: match prefix_group {
-                Some(prefix_str) => match Prefix::from_str(prefix_str) {
-                    Ok(prefix) => Some(prefix),
-                    Err(_) => None
-                },
-                None => None
-            },
 
-            command: match command_group {
-                Some(command_str) => match Command::from_str(command_str){
-                    Ok(command) => command,
-                    Err(_) => return Err(2)
-                },
-                _ => return Err(3) // this shouldn't happen - the regex shouldn't have matched if the command group
-                                   // is empty
-            },
-
-            params: match params_group {
-                Some(params_str) => {
-                    // Tokenize the parameters.
-                    // Tokenizing them is nontrivial, because of the TRAILING parameter pattern.
-                    // So we use regex.
-                    //@TODO this regex fails if any middle parameter has a colon in it
-                    //@TODO or if the trailing parameter has a \r without a following \n
-                    //@todo probably a bad idea to have written this regex, google "irc regex" and replace this
-                    let re = Regex::new(r"^(?P<middles>[^:]+)?(:(?P<trailing>[^\r\n]+))?").unwrap();
-                    let caps = re.captures(params_str).unwrap();
-
-                    let middles_group = caps.name("middles");
-                    let trailing_group = caps.name("trailing");
-
-                    let mut paramvec = Vec::new();
-
-                    match middles_group {
-                        Some(middles_str)   => {
-                            for middle in middles_str.trim().split(" ") {
-                                paramvec.push(FromStr::from_str(middle).unwrap());
-                            }
-                        },
-                        _               => ()
-                    }
+        let mut prefix = None;
+        if rest.starts_with(":") {
+            let (prefix_str, after_prefix) = split_token(&rest[1..]);
+            prefix = match Prefix::from_str(prefix_str) {
+                Ok(prefix) => Some(prefix),
+                Err(_) => return Err(3)
+            };
+            rest = after_prefix;
+        }
 
-                    match trailing_group {
-                        Some(trailing_str)  => { paramvec.push(FromStr::from_str(trailing_str).unwrap()) },
-                        _               => ()
-                    };
+        let (command_str, mut rest) = split_token(rest);
+        if command_str.is_empty() {
+            return Err(1);
+        }
+        let command = match Command::from_str(command_str) {
+            Ok(command) => command,
+            Err(_) => return Err(2)
+        };
 
-                    Some(Params::from(paramvec))
-                },
-                None => None
+        // Middle parameters can't contain spaces, but may contain colons anywhere but at their start
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            if rest.starts_with(":") {
+                params.push(String::from(&rest[1..]));
+                break;
             }
-        })
+            if params.len() == MAX_PARAMS - 1 {
+                params.push(String::from(rest));
+                break;
+            }
+
+            let (param, after_param) = split_token(rest);
+            params.push(String::from(param));
+            rest = after_param;
+        }
+
+        Ok(IrcMessage { tags: tags,
+                        prefix: prefix,
+                        command: command,
+                        params: match params.is_empty() {
+                            true => None,
+                            false => Some(Params::from(params))
+                        } })
     }
 }
 
 // Convert an IrcMessage into a String
 // This implementation dumps out a carriage return and line feed at the end of the command, to make it ready for
-// sending out an IRC connection
+// sending out an IRC connection. Parsing the result gives back an equal IrcMessage
 impl Into<String> for IrcMessage {
-    // Assumes that the last parameter is the trailing parameter
     fn into(self) -> String {
         let mut result = String::new();
 
+        if !self.tags.is_empty() {
+            let tag_strings: Vec<String> = self.tags.iter().map(|(key, value)| match value.is_empty() {
+                true => key.clone(),
+                false => format!("{}={}", key, escape_tag_value(value))
+            }).collect();
+            result.push_str("@");
+            result.push_str(&tag_strings.join(";"));
+            result.push_str(" ");
+        }
+
         if let Some(prefix) = self.prefix {
             let prefix_string: String = prefix.into();
             result.push_str(&prefix_string);
             result.push_str(" ");
         }
-        
-        result.push_str(self.command.into());
-        result.push_str(" ");
+
+        let command_string: String = self.command.into();
+        result.push_str(&command_string);
 
         if let Some(params) = self.params {
-            let params_string: String = params.into();
-            result.push_str(&params_string);
+            if !params.is_empty() {
+                let params_string: String = params.into();
+                result.push_str(" ");
+                result.push_str(&params_string);
+            }
         }
-        
+
         result.push_str("\r\n");
-        
+
         result
     }
 }
 
 
+// Keeps the chat messages we send within Twitch's limits
+// Twitch counts the messages sent over the last window, and locks out accounts that go over their limit
+pub struct MessageRateLimiter {
+    sent_times: VecDeque<Timespec>
+}
+
+impl MessageRateLimiter {
+    pub fn new() -> Self {
+        MessageRateLimiter { sent_times: VecDeque::new() }
+    }
+
+    // The earliest time, no earlier than now, the next message may be sent
+    pub fn next_send_time(&mut self, time_now: Timespec, is_mod: bool) -> Timespec {
+        let window = Duration::milliseconds(MESSAGE_LIMIT_WINDOW);
+        while self.sent_times.front().map_or(false, |&sent_time| sent_time + window <= time_now) {
+            self.sent_times.pop_front();
+        }
+
+        let limit = if is_mod { MOD_MESSAGE_LIMIT } else { MESSAGE_LIMIT };
+        match self.sent_times.len() < limit {
+            true => time_now,
+            // Once enough of the messages in the window have left it
+            false => self.sent_times[self.sent_times.len() - limit] + window
+        }
+    }
+
+    pub fn record_sent(&mut self, sent_time: Timespec) {
+        self.sent_times.push_back(sent_time);
+    }
+}
+
+
+// Where an IRC connection stands, as told to anyone subscribed to its status
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    // Logged in and joined to our channels
+    Connected,
+    // Lost the connection; reconnecting
+    Disconnected,
+    // The server turned down our credentials; we've given up
+    AuthenticationFailed
+}
+
+// Tell every subscriber the connection's status, forgetting any that have gone away
+fn report_status(status_subscribers: &Mutex<Vec<mpsc::Sender<ConnectionStatus>>>, status: ConnectionStatus) {
+    status_subscribers.lock().unwrap().retain(|tx_status| tx_status.send(status).is_ok());
+}
+
+// How servicing a connection ended
+enum ConnectionEnd {
+    // Reading from it failed, eg. the server hung up
+    Lost,
+    // The server asked us to reconnect, eg. as it's about to restart
+    ReconnectRequested,
+    AuthenticationFailed,
+    Killed
+}
+
+
 // Public interface to an IRC connection
 pub struct IrcStream {
     join_handle: thread::JoinHandle<()>,
     rx_privmsg: mpsc::Receiver<IrcMessage>,
     tx_kill: mpsc::Sender<()>,
+    tx_outgoing: mpsc::SyncSender<IrcMessage>,
+    status_subscribers: Arc<Mutex<Vec<mpsc::Sender<ConnectionStatus>>>>
 }
 
 
 impl IrcStream {
-    // Spawn a thread that establishes and maintains an IRC connection
-    pub fn establish(server: String, pass: String, nick: String, channel: String) -> Result<IrcStream, ()> {
-        // Establish a TCP stream with our target server
-        let mut stream = match TcpStream::connect(&server[..]) {
+    // Spawn a thread that establishes and maintains an IRC connection to the given channels, secured as given
+    // Err(1): unable to set up TLS, eg. the CA file is missing or malformed
+    // Err(2): unable to connect to the server
+    pub fn establish(server: String, pass: String, nick: String, channels: Vec<String>, security: Security)
+                     -> Result<IrcStream, u8> {
+        IrcStream::with_timings(server, pass, nick, channels, security, DEFAULT_PING_INTERVAL, DEFAULT_PONG_TIMEOUT,
+                                DEFAULT_INITIAL_DELAY, DEFAULT_MAX_DELAY)
+    }
+
+    // Same as establish, but PINGing the server once it's been quiet for ping_interval milliseconds, reconnecting
+    // when it doesn't PONG within pong_timeout milliseconds, and backing off from initial_delay up to max_delay
+    // milliseconds between reconnection attempts
+    // Err(1): unable to set up TLS, eg. the CA file is missing or malformed
+    // Err(2): unable to connect to the server
+    pub fn with_timings(server: String, pass: String, nick: String, channels: Vec<String>, security: Security,
+                        ping_interval: u32, pong_timeout: u32, initial_delay: u32, max_delay: u32)
+                        -> Result<IrcStream, u8> {
+        let connector = match Connector::new(&security) {
+            Ok(connector) => connector,
+            Err(_) => return Err(1)
+        };
+
+        // Establish a connection with our target server
+        let stream = match connector.connect(&server) {
             Ok(stream) => stream,
-            Err(_) => return Err(())
+            Err(_) => return Err(2)
         };
         
-        // Create two application-local channels: one for passing received privmsgs to our user app,
-        // and one for listening from our user app for a kill command
+        // Create three application-local channels: one for passing received privmsgs to our user app,
+        // one for listening from our user app for a kill command, and a queue of chat messages for us to send
         let (tx_privmsg, rx_privmsg) = mpsc::channel();
         let (tx_kill, rx_kill) = mpsc::channel();
-        
-        // Spawn an IRC stream servicing thread. This thread maintains an IRC connection and
-        // passes chat messages (privmsgs) through one of its channels
+        let (tx_outgoing, rx_outgoing) = mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+        let status_subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        // The servicing thread blocks reading the stream, so a thread of its own writes our chat messages and keeps
+        // the connection alive, on a clone of the stream that the servicing thread replaces whenever it reconnects
+        let writer_stream = Arc::new(Mutex::new(None));
+        // The channels we're a mod or the broadcaster of
+        let mod_channels = Arc::new(Mutex::new(HashSet::new()));
+        let keepalive = Arc::new(Mutex::new(Keepalive::new(ping_interval, pong_timeout, get_time())));
+        {
+            let writer_stream = writer_stream.clone();
+            let mod_channels = mod_channels.clone();
+            let keepalive = keepalive.clone();
+            thread::spawn(move|| IrcStream::service_outgoing(rx_outgoing, writer_stream, mod_channels, keepalive));
+        }
+
+        // Spawn an IRC stream servicing thread. This thread maintains an IRC connection, reconnecting whenever it's
+        // lost, and passes chat messages (privmsgs) through one of its channels
+        let thread_status_subscribers = status_subscribers.clone();
         let join_handle = thread::spawn(move|| {
-            let mut connected = false;
-            let mut awaiting_endofnames = false;
-            
+            let status_subscribers = thread_status_subscribers;
+            let mut backoff = Backoff::new(initial_delay, max_delay);
+            let mut next_stream = Some(stream);
+
             loop {
-                if connected == false && awaiting_endofnames == false {
-                    IrcStream::connect_to_channel(&mut stream, &pass, &nick, &channel);
-                    awaiting_endofnames = true;
-                }
-            
-                // Check for kill signal; kill this thread if received
-                match rx_kill.try_recv() {
-                    Ok(()) => return,
-                    Err(_) => ()
-                }
-                
-                match IrcStream::get_message(&mut stream) {
-                    Ok(message) => match message.command {
-                        // as a bot, all we really care about is:
-                        // has the server acknowledged our connection?
-                        // did the server ping us? if so, pong it
-                        // did another client send a message? if so, pass it to our user
-                        Command::ReplyEndOfNames => {
+                // Reconnect, unless we're still on the connection we made in establish
+                let mut stream = match next_stream.take() {
+                    Some(stream) => stream,
+                    None => match connector.connect(&server) {
+                        Ok(stream) => stream,
+                        Err(_) => {
+                            println!("Unable to reconnect!");
+                            match rx_kill.recv_timeout(to_std_duration(backoff.next_delay())) {
+                                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                                _ => return
+                            }
+                        }
+                    }
+                };
+
+                let mut connected = false;
+                let end = match IrcStream::connect_to_channels(&mut stream, &pass, &nick, &channels) {
+                    Ok(_) => {
+                        keepalive.lock().unwrap().reset(get_time());
+                        *writer_stream.lock().unwrap() = Some(stream.clone());
+
+                        let end = IrcStream::service_connection(&mut stream, &rx_kill, &tx_privmsg, &keepalive,
+                                                                &mod_channels, &mut || {
+                            // Joining a channel means the connection's good, so a later loss starts backing off
+                            // from the beginning
                             connected = true;
-                            awaiting_endofnames = false;
+                            backoff.reset();
+                            report_status(&status_subscribers, ConnectionStatus::Connected);
+                        });
+
+                        *writer_stream.lock().unwrap() = None;
+                        // We're done with this connection either way, even if it's already closed
+                        let _ = stream.shutdown();
+                        if connected {
+                            report_status(&status_subscribers, ConnectionStatus::Disconnected);
                         }
-                        Command::Ping => {
-                            match IrcStream::send_pong(&mut stream) {
-                                Ok(_) => (),
-                                Err(_) => println!("Unable to send pong!")
-                            }
-                        },
-                        Command::Privmsg => {
-                            match tx_privmsg.send(message) {
-                                Ok(_) => (),
-                                Err(err) => println!("Error sending received IRC message to user\
-                                                      app: {}", err)
-                            };
-                        },
-                        _ => ()
+                        end
                     },
-                    Err(num) => match num {
-                        1|2|3 => {
-                            println!("Got error: {}", num);
-                            connected = false;
-                            loop {
-                                match TcpStream::connect(&server[..]) {
-                                    Ok(the_stream) => {
-                                        stream = the_stream;
-                                        break;
-                                    },
-                                    Err(_) => { thread::sleep_ms(100); }
-                                }
-                            }
-                        },
-                        _ => ()
+                    Err(_) => {
+                        println!("Unable to log in!");
+                        ConnectionEnd::Lost
                     }
+                };
+
+                match end {
+                    // Twitch asks us to reconnect when it's about to restart a server we've joined, which we do right
+                    // away; a server that asks before we've even joined gets backed off from like any other
+                    ConnectionEnd::ReconnectRequested if connected => (),
+                    ConnectionEnd::Lost | ConnectionEnd::ReconnectRequested => {
+                        match rx_kill.recv_timeout(to_std_duration(backoff.next_delay())) {
+                            Err(mpsc::RecvTimeoutError::Timeout) => (),
+                            _ => return
+                        }
+                    },
+                    ConnectionEnd::AuthenticationFailed => {
+                        println!("Login authentication failed!");
+                        report_status(&status_subscribers, ConnectionStatus::AuthenticationFailed);
+                        return;
+                    },
+                    ConnectionEnd::Killed => return
                 }
             }
         });
         
-        Ok( IrcStream { join_handle: join_handle, rx_privmsg: rx_privmsg, tx_kill: tx_kill } )
+        Ok( IrcStream { join_handle: join_handle, rx_privmsg: rx_privmsg, tx_kill: tx_kill, tx_outgoing: tx_outgoing,
+                        status_subscribers: status_subscribers } )
+    }
+
+    // Read messages from a logged in connection, and act on them, until the connection ends
+    // on_joined is called once the server's acknowledged our joining our first channel
+    fn service_connection(stream: &mut Connection, rx_kill: &mpsc::Receiver<()>, tx_privmsg: &mpsc::Sender<IrcMessage>,
+                          keepalive: &Mutex<Keepalive>, mod_channels: &Mutex<HashSet<String>>,
+                          on_joined: &mut FnMut()) -> ConnectionEnd {
+        let mut joined = false;
+        let mut reader = LineReader::new();
+
+        loop {
+            // Check for kill signal; kill this thread if received
+            // Reads give up every so often, so that this is checked even while the server's quiet
+            match rx_kill.try_recv() {
+                Ok(()) => return ConnectionEnd::Killed,
+                Err(_) => ()
+            }
+
+            let message = match IrcStream::get_message(&mut reader, stream) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                // A bad line doesn't mean a bad connection
+                Err(3) => {
+                    println!("Skipping a line that isn't UTF-8");
+                    continue;
+                },
+                Err(4) => continue,
+                Err(num) => {
+                    println!("Got error: {}", num);
+                    return ConnectionEnd::Lost;
+                }
+            };
+            keepalive.lock().unwrap().heard(get_time());
+
+            match message.command {
+                // as a bot, all we really care about is:
+                // has the server acknowledged our connection?
+                // did the server turn down our credentials, or ask us to reconnect?
+                // did the server ping us? if so, pong it
+                // did the server answer our ping?
+                // did another client send a message? if so, pass it to our user
+                Command::ReplyEndOfNames => {
+                    if !joined {
+                        joined = true;
+                        on_joined();
+                    }
+                },
+                Command::Notice => {
+                    let text = message.params.as_ref().and_then(|params| params.last()).map_or("", |text| &text[..]);
+                    if text == "Login authentication failed" || text == "Improperly formatted auth" {
+                        return ConnectionEnd::AuthenticationFailed;
+                    }
+                },
+                Command::Reconnect => return ConnectionEnd::ReconnectRequested,
+                Command::Ping => {
+                    match IrcStream::send_pong(stream, message.params) {
+                        Ok(_) => (),
+                        Err(_) => println!("Unable to send pong!")
+                    }
+                },
+                Command::Pong => {
+                    if let Some(token) = message.params.as_ref().and_then(|params| params.last()) {
+                        keepalive.lock().unwrap().pong_received(token, get_time());
+                    }
+                },
+                Command::Privmsg => {
+                    match tx_privmsg.send(message) {
+                        Ok(_) => (),
+                        Err(err) => println!("Error sending received IRC message to user\
+                                              app: {}", err)
+                    };
+                },
+                // Twitch tells us our own state in a channel on joining it, and after each message we send to it
+                Command::UserState => {
+                    let channel = match message.params.as_ref().and_then(|params| params.first()) {
+                        Some(channel) => channel.clone(),
+                        None => continue
+                    };
+                    let has_badge = |name: &str| message.tags.get("badges").map_or(false, |badges| {
+                        badges.split(',').any(|badge| badge.split('/').next() == Some(name))
+                    });
+                    let mod_tag = message.tags.get("mod").map_or(false, |value| value == "1");
+
+                    let mut mod_channels = mod_channels.lock().unwrap();
+                    match mod_tag || has_badge("broadcaster") {
+                        true => { mod_channels.insert(channel); },
+                        false => { mod_channels.remove(&channel); }
+                    }
+                },
+                _ => ()
+            }
+        }
     }
     
     pub fn join(self) {
@@ -413,9 +745,86 @@ impl IrcStream {
     pub fn kill(&self) {
         self.tx_kill.send(());
     }
+
+    // Hear about the connection's status whenever it changes
+    pub fn subscribe_status(&self) -> mpsc::Receiver<ConnectionStatus> {
+        let (tx_status, rx_status) = mpsc::channel();
+        self.status_subscribers.lock().unwrap().push(tx_status);
+        rx_status
+    }
+
+    // Queue a chat message for the given channel, with the given tags, to be sent as soon as Twitch's limits allow
+    // Err(1): too many messages are queued already
+    // Err(2): the connection has stopped sending messages
+    pub fn send_privmsg(&self, channel: String, tags: Tags, text: String) -> Result<(), u8> {
+        let message = IrcMessage { tags: tags, prefix: None, command: Command::Privmsg,
+                                   params: Some(Params::from(vec![channel, text])) };
+
+        match self.tx_outgoing.try_send(message) {
+            Ok(_) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => Err(1),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(2)
+        }
+    }
+
+    // Send queued chat messages, waiting out Twitch's limits, and keep the connection alive, until the IrcStream is
+    // dropped
+    // Messages queued while we're disconnected are dropped. A connection found dead is shut down, so that the servicing
+    // thread stops waiting on it and reconnects
+    fn service_outgoing(rx_outgoing: mpsc::Receiver<IrcMessage>, writer_stream: Arc<Mutex<Option<Connection>>>,
+                        mod_channels: Arc<Mutex<HashSet<String>>>, keepalive: Arc<Mutex<Keepalive>>) {
+        let mut rate_limiter = MessageRateLimiter::new();
+        let check_interval = std::time::Duration::from_millis(KEEPALIVE_CHECK_INTERVAL);
+
+        loop {
+            match rx_outgoing.recv_timeout(check_interval) {
+                Ok(message) => {
+                    let is_mod = match message.params.as_ref().and_then(|params| params.first()) {
+                        Some(channel) => mod_channels.lock().unwrap().contains(channel),
+                        None => false
+                    };
+                    let send_time = rate_limiter.next_send_time(get_time(), is_mod);
+                    thread::sleep(to_std_duration(send_time - get_time()));
+
+                    match *writer_stream.lock().unwrap() {
+                        Some(ref mut stream) => match IrcStream::send_message(stream, message) {
+                            Ok(_) => rate_limiter.record_sent(get_time()),
+                            Err(_) => println!("Unable to send chat message!")
+                        },
+                        None => println!("Not connected; dropping chat message")
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return
+            }
+
+            let mut writer_stream = writer_stream.lock().unwrap();
+            let dead = match *writer_stream {
+                Some(ref mut stream) => match keepalive.lock().unwrap().poll(get_time()) {
+                    KeepaliveAction::SendPing(token) => {
+                        match IrcStream::send_ping(stream, token) {
+                            Ok(_) => (),
+                            Err(_) => println!("Unable to send ping!")
+                        }
+                        false
+                    },
+                    KeepaliveAction::Dead => {
+                        println!("Server stopped answering pings; reconnecting");
+                        let _ = stream.shutdown();
+                        true
+                    },
+                    KeepaliveAction::Wait => false
+                },
+                None => false
+            };
+            if dead {
+                *writer_stream = None;
+            }
+        }
+    }
     
     // Consider making a Message serializer...
-    fn send_message(stream: &mut TcpStream, message: IrcMessage) -> Result<(), ()> {
+    fn send_message(stream: &mut Connection, message: IrcMessage) -> Result<(), ()> {
         let message_string: String = message.into();
         
         match stream.write_all(message_string.as_bytes()) {
@@ -424,9 +833,22 @@ impl IrcStream {
         }
     }
     
-    fn send_credentials(stream: &mut TcpStream, pass: &String, nick: &String) -> Result<(), ()> {
-        let pass_message = IrcMessage { prefix: None, command: Command::Pass, params: Some(Params::from(vec![pass.clone()])) };
-        let nick_message = IrcMessage { prefix: None, command: Command::Nick, params: Some(Params::from(vec![nick.clone()])) };
+    // Ask for Twitch's capabilities; we don't wait for the server's ACK, since Twitch handles CAP before PASS
+    fn send_capability_request(stream: &mut Connection) -> Result<(), ()> {
+        let cap_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Cap,
+                                       params: Some(Params::from(vec![String::from("REQ"),
+                                                                      String::from(TWITCH_CAPABILITIES)])) };
+
+        try!(IrcStream::send_message(stream, cap_message));
+
+        Ok(())
+    }
+
+    fn send_credentials(stream: &mut Connection, pass: &String, nick: &String) -> Result<(), ()> {
+        let pass_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Pass,
+                                        params: Some(Params::from(vec![pass.clone()])) };
+        let nick_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Nick,
+                                        params: Some(Params::from(vec![nick.clone()])) };
         
         try!(IrcStream::send_message(stream, pass_message));
         try!(IrcStream::send_message(stream, nick_message));
@@ -434,90 +856,338 @@ impl IrcStream {
         Ok(())
     }
 
-    fn send_join(stream: &mut TcpStream, channel: &String) -> Result<(), ()> {
-        let join_message = IrcMessage { prefix: None, command: Command::Join, params: Some(Params::from(vec![channel.clone()])) };
+    fn send_join(stream: &mut Connection, channel: &String) -> Result<(), ()> {
+        let join_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Join,
+                                        params: Some(Params::from(vec![channel.clone()])) };
         
         try!(IrcStream::send_message(stream, join_message));
         
         Ok(())
     }
 
-    fn send_pong(stream: &mut TcpStream) -> Result<(), ()> {
-        let pong_message = IrcMessage { prefix: None, command: Command::Pong, params: None };
+    // Answer a PING, echoing its parameters
+    fn send_pong(stream: &mut Connection, ping_params: Option<Params>) -> Result<(), ()> {
+        let pong_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Pong, params: ping_params };
         
         try!(IrcStream::send_message(stream, pong_message));
         
         Ok(())
     }
+
+    fn send_ping(stream: &mut Connection, token: String) -> Result<(), ()> {
+        let ping_message = IrcMessage { tags: Tags::new(), prefix: None, command: Command::Ping,
+                                        params: Some(Params::from(vec![token])) };
+
+        try!(IrcStream::send_message(stream, ping_message));
+
+        Ok(())
+    }
     
-    fn connect_to_channel(stream: &mut TcpStream, pass: &String, nick: &String, channel: &String) {
+    // Log in and join the channels; whether the server accepts our credentials, we only hear later
+    fn connect_to_channels(stream: &mut Connection, pass: &String, nick: &String, channels: &[String])
+                           -> Result<(), ()> {
+        try!(IrcStream::send_capability_request(stream));
+
         // Send the server our credentials
-        //@todo implement log in failure recovery
-        match IrcStream::send_credentials(stream, pass, nick) {
-            Ok(_) => (),
-            Err(_) => panic!("Unable to send credentials!")
-        }
-        
-        match IrcStream::send_join(stream, channel) {
-            Ok(_) => (),
-            Err(_) => panic!("Unable to join target channel!")
+        try!(IrcStream::send_credentials(stream, pass, nick));
+
+        for channel in channels.iter() {
+            try!(IrcStream::send_join(stream, channel));
         }
+
+        Ok(())
     }
     
-    // Get a message from an IRC channel.
-    //@todo make this nonblocking - any way to do this without function-local static?
+    // Get the next message from the server, if one arrives within the connection's read poll interval
     // Err(1): stream EOF - closed by other party
-    // Err(2): TCP read error - probably need to reconnect socket
-    // Err(3): stream received a non-UTF8 character?
-    // Err(4): received unrecognized message
-    fn get_message(stream: &mut TcpStream) -> Result<IrcMessage, u8> {
-        // Receive a message from the server as raw bytes.
-        // We'll convert it to a String once we've received the whole thing, to simplify parsing
-        let mut response: Vec<u8> = Vec::new();
-
-        // Read from the TCP stream until we get CRLF (which signals the termination of an IRC message)
-        // or the socket read fails
-        loop {
-            let mut read_byte_vec: Vec<u8> = Vec::with_capacity(1);
-            
-            let mut read_adaptor = stream.take(1);
-            let read_result = read_adaptor.read_to_end(&mut read_byte_vec);
-            match read_result {
-                Ok(_) => {
-                    match read_byte_vec.get(0) {
-                        Some(byte) => response.push(*byte),
-                        None => return Err(1)
-                    }
-                    if last_two_are_crlf(&response) {
-                        // Convert our raw byte vector into a String for easier, native processing
-                        //@TODO Better handle invalid messages
-                        match String::from_utf8(response) {
-                            Ok(msg_str) => {
-                                match IrcMessage::from_str(&msg_str) {
-                                    Ok(msg) => return Ok(msg),
-                                    _ => return Err(4)
-                                }
-                            },
-                            _ => return Err(3)
-                        }
-                    }
-                },
-                Err(_) => return Err(2)
-            }
+    // Err(2): read error - probably need to reconnect socket
+    // Err(3): received a line that isn't UTF-8
+    // Err(4): received malformed message
+    fn get_message(reader: &mut LineReader, stream: &mut Connection) -> Result<Option<IrcMessage>, u8> {
+        let line = match reader.read_line(stream) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(None),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(1),
+            Err(_) => return Err(2)
+        };
+
+        match String::from_utf8(line) {
+            Ok(line) => match IrcMessage::from_str(&line) {
+                Ok(message) => Ok(Some(message)),
+                Err(_) => Err(4)
+            },
+            Err(_) => Err(3)
         }
     }
 }
 
 
-fn last_two_are_crlf(vec: &Vec<u8>) -> bool {
-    let vec_len = vec.len();
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use std;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use time::{Timespec, Duration};
+
+    use tmi::irc::{IrcStream, IrcMessage, Prefix, Params, Tags, Command, MessageRateLimiter, ConnectionStatus};
+    use tmi::irc::connection::Security;
+
+    #[test]
+    fn test_tags_are_parsed_and_unescaped() {
+        let line = "@badges=moderator/1,subscriber/12;bits=100;display-name=Some\\sUser;emotes=;\
+                    system-msg=a\\:b\\\\c\\ :someuser!someuser@someuser.tmi.twitch.tv PRIVMSG #channel :hi there\r\n";
+        let message = IrcMessage::from_str(line).unwrap();
+
+        assert_eq!(message.tags.get("badges").map(|s| &s[..]), Some("moderator/1,subscriber/12"));
+        assert_eq!(message.tags.get("bits").map(|s| &s[..]), Some("100"));
+        assert_eq!(message.tags.get("display-name").map(|s| &s[..]), Some("Some User"));
+        assert_eq!(message.tags.get("emotes").map(|s| &s[..]), Some(""));
+        assert_eq!(message.tags.get("system-msg").map(|s| &s[..]), Some("a;b\\c"));
+        assert_eq!(message.prefix.unwrap().servername_nick, "someuser");
+        assert_eq!(message.params.unwrap()[1], "hi there");
+
+        // Tags survive being written back out
+        let mut tags = Tags::new();
+        tags.insert(String::from("msg"), String::from("a b;c"));
+        let out: String = IrcMessage { tags: tags, prefix: None, command: Command::Pong, params: None }.into();
+        assert_eq!(out, "@msg=a\\sb\\:c PONG\r\n");
+    }
+
+    // Lines as Twitch sends them, and the command and parameters each should parse into
+    const TWITCH_CORPUS: [(&'static str, Command, &'static [&'static str]); 15] = [
+        (":tmi.twitch.tv 001 tppbot :Welcome, GLHF!\r\n", Command::ReplyWelcome, &["tppbot", "Welcome, GLHF!"]),
+        (":tmi.twitch.tv 372 tppbot :You are in a maze of twisty passages, all alike.\r\n",
+         Command::Numeric(372), &["tppbot", "You are in a maze of twisty passages, all alike."]),
+        (":tmi.twitch.tv CAP * ACK :twitch.tv/tags twitch.tv/commands twitch.tv/membership\r\n",
+         Command::Cap, &["*", "ACK", "twitch.tv/tags twitch.tv/commands twitch.tv/membership"]),
+        (":tppbot!tppbot@tppbot.tmi.twitch.tv JOIN #channel\r\n", Command::Join, &["#channel"]),
+        (":tppbot.tmi.twitch.tv 353 tppbot = #channel :tppbot\r\n", Command::Numeric(353),
+         &["tppbot", "=", "#channel", "tppbot"]),
+        (":tppbot.tmi.twitch.tv 366 tppbot #channel :End of /NAMES list\r\n", Command::ReplyEndOfNames,
+         &["tppbot", "#channel", "End of /NAMES list"]),
+        ("PING :tmi.twitch.tv\r\n", Command::Ping, &["tmi.twitch.tv"]),
+        ("@badge-info=;badges=broadcaster/1;color=#0D4200;display-name=Streamer;emotes=;id=b34ccfc7-4977-403a-8a94-\
+          33c6bac34fb8;mod=0;room-id=1337;subscriber=0;tmi-sent-ts=1507246572675;turbo=0;user-id=1337;user-type= \
+          :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer :up2 a b250ms\r\n",
+         Command::Privmsg, &["#streamer", "up2 a b250ms"]),
+        (":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel ::) start:select\r\n", Command::Privmsg,
+         &["#channel", ":) start:select"]),
+        ("@msg-id=slow_off :tmi.twitch.tv NOTICE #channel :This room is no longer in slow mode.\r\n", Command::Notice,
+         &["#channel", "This room is no longer in slow mode."]),
+        (":tmi.twitch.tv NOTICE * :Login authentication failed\r\n", Command::Notice,
+         &["*", "Login authentication failed"]),
+        (":tmi.twitch.tv RECONNECT\r\n", Command::Reconnect, &[]),
+        ("@ban-duration=350;room-id=12345678;target-user-id=87654321;tmi-sent-ts=1642719320727 \
+          :tmi.twitch.tv CLEARCHAT #channel :spammer\r\n", Command::ClearChat, &["#channel", "spammer"]),
+        ("@badge-info=;badges=;color=;display-name=tppbot;emote-sets=0;mod=0;subscriber=0;user-type= \
+          :tmi.twitch.tv USERSTATE #channel\r\n", Command::UserState, &["#channel"]),
+        (":tmi.twitch.tv HOSTTARGET #channel :otherchannel 10\r\n", Command::HostTarget,
+         &["#channel", "otherchannel 10"])
+    ];
+
+    #[test]
+    fn test_twitch_corpus_parses_and_round_trips() {
+        for &(line, ref command, params) in TWITCH_CORPUS.iter() {
+            let message = IrcMessage::from_str(line).unwrap();
+            let expected_params: Vec<&str> = params.iter().cloned().collect();
+            let parsed_params: Vec<&str> = match message.params {
+                Some(ref parsed_params) => parsed_params.iter().map(|param| &param[..]).collect(),
+                None => Vec::new()
+            };
 
-    if vec_len < 2 {
-        return false;
+            assert_eq!(&message.command, command);
+            assert_eq!(parsed_params, expected_params);
+
+            let written: String = message.clone().into();
+            assert!(written.ends_with("\r\n"));
+            assert_eq!(IrcMessage::from_str(&written), Ok(message));
+        }
+
+        // Commands we don't know are kept, and so are colons in middle parameters, and a CR other than the line's
+        let line = ":tmi.twitch.tv SOMETHINGNEW #chan:nel a:b :line\rbreak";
+        let message = IrcMessage::from_str(line).unwrap();
+        assert_eq!(message.command, Command::Other(String::from("SOMETHINGNEW")));
+        assert_eq!(message.params, Some(Params::from(vec![String::from("#chan:nel"), String::from("a:b"),
+                                                          String::from("line\rbreak")])));
+        let written: String = message.clone().into();
+        assert_eq!(IrcMessage::from_str(&written), Ok(message));
+
+        // Prefixes are split into their parts
+        let message = IrcMessage::from_str(TWITCH_CORPUS[3].0).unwrap();
+        assert_eq!(message.prefix, Some(Prefix { servername_nick: String::from("tppbot"),
+                                                 user: Some(String::from("tppbot")),
+                                                 host: Some(String::from("tppbot.tmi.twitch.tv")) }));
+
+        assert_eq!(IrcMessage::from_str("\r\n").err(), Some(1));
+        assert_eq!(IrcMessage::from_str(":tmi.twitch.tv\r\n").err(), Some(1));
+        assert_eq!(IrcMessage::from_str(":tmi.twitch.tv PRIV-MSG #channel\r\n").err(), Some(2));
+        assert_eq!(IrcMessage::from_str(":!user@host PING\r\n").err(), Some(3));
+    }
+
+    #[test]
+    fn test_message_rate_limiter_keeps_to_twitchs_limits() {
+        let start = Timespec::new(0, 0);
+        let mut limiter = MessageRateLimiter::new();
+
+        // 20 messages a second apart fill the window...
+        for second in 0..20 {
+            let time_now = start + Duration::seconds(second);
+            assert_eq!(limiter.next_send_time(time_now, false), time_now);
+            limiter.record_sent(time_now);
+        }
+
+        // ...so the next has to wait until the first leaves it, unless we're a mod
+        let time_now = start + Duration::seconds(20);
+        assert_eq!(limiter.next_send_time(time_now, false), start + Duration::seconds(30));
+        assert_eq!(limiter.next_send_time(time_now, true), time_now);
+        assert_eq!(limiter.next_send_time(start + Duration::seconds(31), false), start + Duration::seconds(31));
+    }
+
+    fn read_line(server: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        server.read_line(&mut line).unwrap();
+        line
     }
 
-    match (vec[vec_len-2], vec[vec_len-1]) {
-        (b'\r', b'\n')  => true,
-        _               => false
+    // Stand in for Twitch: accept a connection, and expect us to log in and join
+    fn expect_login(listener: &TcpListener) -> BufReader<TcpStream> {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut server = BufReader::new(stream);
+
+        for &expected in ["CAP REQ :twitch.tv/tags twitch.tv/commands twitch.tv/membership\r\n", "PASS oauth:test\r\n",
+                          "NICK tppbot\r\n", "JOIN #channel\r\n", "JOIN #partner\r\n"].iter() {
+            assert_eq!(read_line(&mut server), expected);
+        }
+
+        server
     }
-}
\ No newline at end of file
+
+    // Same as expect_login, then tell us the names list of our first channel is over
+    fn accept_login(listener: &TcpListener) -> BufReader<TcpStream> {
+        let mut server = expect_login(listener);
+        server.get_mut().write_all(b":tmi.twitch.tv 366 tppbot #channel :End of /NAMES list\r\n").unwrap();
+        server
+    }
+
+    fn test_channels() -> Vec<String> {
+        vec![String::from("#channel"), String::from("#partner")]
+    }
+
+    // Connect to a scripted server, waiting up to reconnect_delay milliseconds between reconnection attempts
+    fn establish_test_stream(listener: &TcpListener, reconnect_delay: u32) -> IrcStream {
+        IrcStream::with_timings(listener.local_addr().unwrap().to_string(), String::from("oauth:test"),
+                                String::from("tppbot"), test_channels(), Security::Plain, 300, 300, reconnect_delay,
+                                reconnect_delay).unwrap()
+    }
+
+    #[test]
+    fn test_keepalive_against_a_scripted_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let irc_stream = establish_test_stream(&listener, 10);
+        let mut server = accept_login(&listener);
+
+        // The server's PINGs are answered with their token
+        server.get_mut().write_all(b"PING :tmi.twitch.tv\r\n").unwrap();
+        assert_eq!(read_line(&mut server), "PONG tmi.twitch.tv\r\n");
+
+        // Once the server's been quiet a while, we PING it, and again once it's answered and been quiet again
+        assert_eq!(read_line(&mut server), "PING tppm-1\r\n");
+        server.get_mut().write_all(b":tmi.twitch.tv PONG tmi.twitch.tv :tppm-1\r\n").unwrap();
+        assert_eq!(read_line(&mut server), "PING tppm-2\r\n");
+
+        // Left unanswered, we hang up and log in all over again
+        assert_eq!(read_line(&mut server), "");
+        accept_login(&listener);
+
+        irc_stream.kill();
+    }
+
+    #[test]
+    fn test_reconnects_and_status_against_a_scripted_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let irc_stream = establish_test_stream(&listener, 10);
+        let statuses = irc_stream.subscribe_status();
+        let next_status = || statuses.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+
+        let mut server = accept_login(&listener);
+        assert_eq!(next_status(), ConnectionStatus::Connected);
+
+        // Twitch asks us to reconnect before it restarts, and we do so right away
+        server.get_mut().write_all(b":tmi.twitch.tv RECONNECT\r\n").unwrap();
+        assert_eq!(next_status(), ConnectionStatus::Disconnected);
+        let server = accept_login(&listener);
+        assert_eq!(next_status(), ConnectionStatus::Connected);
+
+        // Hanging up on us has us back off a little before trying again
+        drop(server);
+        assert_eq!(next_status(), ConnectionStatus::Disconnected);
+        let mut server = expect_login(&listener);
+
+        // Turning down our credentials has us give up for good
+        server.get_mut().write_all(b":tmi.twitch.tv NOTICE * :Login authentication failed\r\n").unwrap();
+        assert_eq!(next_status(), ConnectionStatus::AuthenticationFailed);
+        irc_stream.join();
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn test_backs_off_from_reconnect_requests_before_joining() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let irc_stream = establish_test_stream(&listener, 60000);
+
+        // Asked to reconnect before we've joined, we hang up, and wait out the delay rather than reconnecting, so
+        // that being killed meanwhile ends it there
+        let mut server = expect_login(&listener);
+        server.get_mut().write_all(b":tmi.twitch.tv RECONNECT\r\n").unwrap();
+        assert_eq!(read_line(&mut server), "");
+        irc_stream.kill();
+        irc_stream.join();
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn test_bursts_and_bad_lines_and_prompt_kills() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let irc_stream = IrcStream::establish(listener.local_addr().unwrap().to_string(), String::from("oauth:test"),
+                                              String::from("tppbot"), test_channels(), Security::Plain).unwrap();
+        let statuses = irc_stream.subscribe_status();
+        let mut server = accept_login(&listener);
+        assert_eq!(statuses.recv_timeout(std::time::Duration::from_secs(5)), Ok(ConnectionStatus::Connected));
+
+        // A burst of chat in one go, with a line that isn't UTF-8, and one ending without a CR
+        let mut burst = Vec::new();
+        for i in 0..1000 {
+            let line = format!(":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :a{}\r\n", i);
+            burst.extend_from_slice(line.as_bytes());
+            if i == 500 {
+                burst.extend_from_slice(b":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :\xff\xfe\r\n");
+            }
+        }
+        burst.extend_from_slice(b":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :last\n");
+        server.get_mut().write_all(&burst).unwrap();
+
+        let next_text = || {
+            let message = irc_stream.rx_privmsg.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+            message.params.unwrap().pop().unwrap()
+        };
+        for i in 0..1000 {
+            assert_eq!(next_text(), format!("a{}", i));
+        }
+        assert_eq!(next_text(), "last");
+
+        // The bad line didn't cost us the connection
+        assert!(statuses.try_recv().is_err());
+
+        // Killing the stream takes effect right away, even though the server's gone quiet
+        let killed_at = std::time::Instant::now();
+        irc_stream.kill();
+        irc_stream.join();
+        assert!(killed_at.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
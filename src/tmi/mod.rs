@@ -2,9 +2,18 @@ mod irc;
 
 use std::fs::File;
 use std::io::Read;
+use std::sync::mpsc;
 
 use toml;
 
+use self::irc::Tags;
+use self::irc::connection::Security;
+pub use self::irc::ConnectionStatus;
+
+
+// Twitch drops chat messages longer than this many characters
+const MAX_MESSAGE_LENGTH: usize = 500;
+
 
 // Err(1): unable to find value in tree
 // Err(2): unable to parse value as string
@@ -18,11 +27,71 @@ fn get_toml_value_as_string(tree: &toml::Value, value: &str) -> Result<String, u
     }
 }
 
-// Parse the TPPM toml configuration file; return the server, password, nick, and channel
+// What we do with the chat in a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelPolicy {
+    // Chat's inputs and mode votes are taken
+    pub input: bool,
+    // Mods' commands are taken
+    pub mod_commands: bool,
+    // Nothing is taken, and nothing is said; chat's only logged
+    pub read_only: bool
+}
+
+// The channels to join, with their policies, from irc.channel and irc.channels; see tppm.toml.example
+// irc.channel is our own channel, where everything's taken; the channels in irc.channels take inputs but not mods'
+// commands unless they say otherwise. Our own channel comes first, or failing that the first of irc.channels is
+// taken to be ours. Names are lowercased, and given a '#' if they're missing one
+// Err(3): no channels given, or a channel's malformed
+pub fn parse_channels(tree: &toml::Value) -> Result<Vec<(String, ChannelPolicy)>, u8> {
+    let mut channels: Vec<(String, ChannelPolicy)> = Vec::new();
+    let mut add_channel = |name: &str, policy: ChannelPolicy| {
+        let name = format!("#{}", name.trim_left_matches('#').to_lowercase());
+        if name.len() > 1 && !channels.iter().any(|&(ref known_name, _)| *known_name == name) {
+            channels.push((name, policy));
+        }
+    };
+
+    if let Some(channel_value) = tree.lookup("irc.channel") {
+        match channel_value.as_str() {
+            Some(channel) => add_channel(channel, ChannelPolicy { input: true, mod_commands: true, read_only: false }),
+            None => return Err(3)
+        }
+    }
+
+    if let Some(channels_value) = tree.lookup("irc.channels") {
+        let channels_slice = match channels_value.as_slice() {
+            Some(slice) => slice,
+            None => return Err(3)
+        };
+        for channel_value in channels_slice.iter() {
+            let name = match channel_value.lookup("name").and_then(|value| value.as_str()) {
+                Some(name) => name,
+                None => return Err(3)
+            };
+            let get_flag = |key: &str, default: bool| match channel_value.lookup(key) {
+                Some(value) => value.as_bool().ok_or(3),
+                None => Ok(default)
+            };
+            let read_only = try!(get_flag("read_only", false));
+            add_channel(name, ChannelPolicy { input: try!(get_flag("input", true)) && !read_only,
+                                              mod_commands: try!(get_flag("mod_commands", false)) && !read_only,
+                                              read_only: read_only });
+        }
+    }
+
+    match channels.is_empty() {
+        true => Err(3),
+        false => Ok(channels)
+    }
+}
+
+// Parse the TPPM toml configuration file; return the server, password, nick, channels with their policies, and how to
+// secure the connection
 // Err(1): Unable to open config file
 // Err(2): Unable to parse config file as TOML
 // Err(3): Required parameter missing or malformed
-fn parse_config_file(path: &str) -> Result<(String, String, String, String), u8> {
+fn parse_config_file(path: &str) -> Result<(String, String, String, Vec<(String, ChannelPolicy)>, Security), u8> {
     let mut config_file = match File::open(path) {
         Ok(file) => file,
         Err(_) => return Err(1)
@@ -51,60 +120,251 @@ fn parse_config_file(path: &str) -> Result<(String, String, String, String), u8>
         Err(_) => return Err(3)
     };
 
-    let channel = match get_toml_value_as_string(&toml_tree, "irc.channel") {
-        Ok(channel) => channel,
-        Err(_) => return Err(3)
+    let channels = try!(parse_channels(&toml_tree));
+
+    // Connections are in the clear unless TLS is asked for
+    let security = match toml_tree.lookup("irc.tls") {
+        Some(tls_value) => match tls_value.as_bool() {
+            Some(true) => match toml_tree.lookup("irc.tls_ca_file") {
+                Some(ca_file_value) => match ca_file_value.as_str() {
+                    Some(ca_file) => Security::Tls(Some(String::from(ca_file))),
+                    None => return Err(3)
+                },
+                None => Security::Tls(None)
+            },
+            Some(false) => Security::Plain,
+            None => return Err(3)
+        },
+        None => Security::Plain
     };
 
-    Ok((server, pass, nick, channel))
+    Ok((server, pass, nick, channels, security))
+}
+
+
+// A chat message, with what Twitch's tags say about it and its sender
+pub struct ChatMessage {
+    // The channel it was sent in, eg. "#channel"
+    pub channel: String,
+    // The sender's login name
+    pub sender: String,
+    pub display_name: Option<String>,
+    pub user_id: Option<String>,
+    // eg. "moderator/1", "subscriber/12"
+    pub badges: Vec<String>,
+    // Bits cheered with the message, if any
+    pub bits: Option<u32>,
+    pub message_id: Option<String>,
+    pub message: String
+}
+
+
+// Split text into pieces of at most max_length characters, between words where possible
+// Runs of whitespace between words are collapsed into a single space
+fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut piece_length = 0;
+
+    for word in text.split_whitespace() {
+        let word_length = word.chars().count();
+        if piece_length > 0 && piece_length + 1 + word_length > max_length {
+            pieces.push(piece);
+            piece = String::new();
+            piece_length = 0;
+        }
+        if piece_length > 0 {
+            piece.push(' ');
+            piece_length += 1;
+        }
+
+        // Words too long for a piece of their own are broken up wherever they have to be
+        for c in word.chars() {
+            if piece_length == max_length {
+                pieces.push(piece);
+                piece = String::new();
+                piece_length = 0;
+            }
+            piece.push(c);
+            piece_length += 1;
+        }
+    }
+    if piece_length > 0 {
+        pieces.push(piece);
+    }
+
+    pieces
 }
 
 
 pub struct TmiStream {
-    irc_stream: irc::IrcStream
+    irc_stream: irc::IrcStream,
+    channel_policies: Vec<(String, ChannelPolicy)>
 }
 
 impl TmiStream {
     // Err(1): parsing TOML file failed
     // Err(2): establishing IRC stream failed
+    // Err(3): setting up TLS failed, eg. the CA file is missing or malformed
     pub fn establish(path: &str) -> Result<Self, u8> {
         // Parse our configuration file
-        let (server, pass, nick, channel) = match parse_config_file(path) {
-            Ok((server, pass, nick, channel)) => (server, pass, nick, channel),
+        let (server, pass, nick, channel_policies, security) = match parse_config_file(path) {
+            Ok(config) => config,
             Err(_) => return Err(1)
         };
 
-        match irc::IrcStream::establish(server, pass, nick, channel) {
-            Ok(irc_stream) => Ok(TmiStream { irc_stream: irc_stream} ),
+        let channels = channel_policies.iter().map(|&(ref channel, _)| channel.clone()).collect();
+        match irc::IrcStream::establish(server, pass, nick, channels, security) {
+            Ok(irc_stream) => Ok(TmiStream { irc_stream: irc_stream, channel_policies: channel_policies } ),
+            Err(1) => Err(3),
             Err(_) => Err(2)
         }
     }
 
-    // Ok((sender, message))
     // Err(1): unable to identify sender
     // Err(2): unable to identify message payload
     // Err(3): unable to identify message payload
     // Err(4): unable to receive message
-    pub fn receive(&self) -> Result<(String, String), u8> {
+    pub fn receive(&self) -> Result<ChatMessage, u8> {
         match self.irc_stream.receive_privmsg() {
             Ok(msg) => {
                 let nick = match msg.prefix {
                     Some(prefix) => prefix.servername_nick,
                     None => return Err(1)
                 };
-                let message = match msg.params {
+                let (channel, message) = match msg.params {
                     Some(mut params) => {
                         if params.len() < 2 {
                             return Err(2)
                         } else {
-                            params.remove(1)
+                            let message = params.remove(1);
+                            (params.remove(0).to_lowercase(), message)
                         }
                     },
                     None => return Err(3)
                 };
-                Ok((nick, message))
+
+                // Twitch leaves tags empty rather than out when they don't apply
+                let tags = msg.tags;
+                let get_tag = |key: &str| tags.get(key).and_then(|value| match value.is_empty() {
+                    true => None,
+                    false => Some(value.clone())
+                });
+
+                Ok(ChatMessage { channel: channel,
+                                 sender: nick,
+                                 display_name: get_tag("display-name"),
+                                 user_id: get_tag("user-id"),
+                                 badges: match get_tag("badges") {
+                                     Some(badges) => badges.split(',').map(String::from).collect(),
+                                     None => Vec::new()
+                                 },
+                                 bits: get_tag("bits").and_then(|bits| bits.parse().ok()),
+                                 message_id: get_tag("id"),
+                                 message: message })
             },
             Err(_) => Err(4)
         }
     }
+
+    // Hear about the connection's status whenever it changes
+    pub fn subscribe_status(&self) -> mpsc::Receiver<ConnectionStatus> {
+        self.irc_stream.subscribe_status()
+    }
+
+    // What we do with the chat in the given channel; channels we haven't joined are read-only
+    pub fn get_channel_policy(&self, channel: &str) -> ChannelPolicy {
+        match self.channel_policies.iter().find(|&&(ref name, _)| name == channel) {
+            Some(&(_, policy)) => policy,
+            None => ChannelPolicy { input: false, mod_commands: false, read_only: true }
+        }
+    }
+
+    // Say something in the given channel
+    // Messages too long for Twitch are split up, and every part is queued to be sent as soon as Twitch's limits allow
+    // Err(1): too many messages are queued already
+    // Err(2): the connection has stopped sending messages
+    // Err(3): the channel is read-only, or one we haven't joined
+    pub fn say(&self, channel: &str, message: &str) -> Result<(), u8> {
+        self.send(channel, Tags::new(), message)
+    }
+
+    // Reply to a chat message, in a thread under it when we know its id, or mentioning its sender otherwise
+    // Err(1): too many messages are queued already
+    // Err(2): the connection has stopped sending messages
+    // Err(3): the message's channel is read-only
+    pub fn reply(&self, to: &ChatMessage, message: &str) -> Result<(), u8> {
+        match to.message_id {
+            Some(ref message_id) => {
+                let mut tags = Tags::new();
+                tags.insert(String::from("reply-parent-msg-id"), message_id.clone());
+                self.send(&to.channel, tags, message)
+            },
+            None => {
+                let name = to.display_name.as_ref().unwrap_or(&to.sender);
+                self.send(&to.channel, Tags::new(), &format!("@{} {}", name, message))
+            }
+        }
+    }
+
+    fn send(&self, channel: &str, tags: Tags, message: &str) -> Result<(), u8> {
+        if self.get_channel_policy(channel).read_only {
+            return Err(3);
+        }
+        for piece in split_message(message, MAX_MESSAGE_LENGTH) {
+            try!(self.irc_stream.send_privmsg(String::from(channel), tags.clone(), piece));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use tmi::{split_message, parse_channels, ChannelPolicy};
+
+    #[test]
+    fn test_long_messages_are_split_between_words() {
+        assert_eq!(split_message("short message", 500), vec!["short message"]);
+        assert_eq!(split_message("one two  three four", 9), vec!["one two", "three", "four"]);
+        assert_eq!(split_message("a abcdefghij b", 4), vec!["a", "abcd", "efgh", "ij b"]);
+        assert_eq!(split_message("   ", 500), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_channels_and_their_policies() {
+        let tree: toml::Value = r##"
+            [irc]
+            channel = "#TheStreamer"
+
+            [[irc.channels]]
+            name = "partner"
+
+            [[irc.channels]]
+            name = "#Trusted_Partner"
+            mod_commands = true
+
+            [[irc.channels]]
+            name = "#lurked"
+            input = true
+            read_only = true
+
+            [[irc.channels]]
+            name = "#thestreamer"
+            read_only = true
+        "##.parse().unwrap();
+        let full = ChannelPolicy { input: true, mod_commands: true, read_only: false };
+        assert_eq!(parse_channels(&tree), Ok(vec![
+            (String::from("#thestreamer"), full),
+            (String::from("#partner"), ChannelPolicy { input: true, mod_commands: false, read_only: false }),
+            (String::from("#trusted_partner"), full),
+            (String::from("#lurked"), ChannelPolicy { input: false, mod_commands: false, read_only: true })]));
+
+        let tree: toml::Value = "[irc]\nserver = \"irc.chat.twitch.tv:6697\"".parse().unwrap();
+        assert_eq!(parse_channels(&tree), Err(3));
+        let tree: toml::Value = "[[irc.channels]]\ninput = false".parse().unwrap();
+        assert_eq!(parse_channels(&tree), Err(3));
+    }
 }